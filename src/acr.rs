@@ -0,0 +1,188 @@
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use http::{Method, Request};
+use http::header::CONTENT_TYPE;
+use http_body_util::{BodyExt, Full};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use tracing::debug;
+use url::{form_urlencoded, Url};
+
+use crate::{upstream_client, BoxError, UpstreamClient};
+use crate::credentials::{secret_var, BoxFuture, CredentialProvider, ScopedTokenCache, TokenCache};
+
+const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com";
+
+/// The audience of Azure AD tokens that ACR exchanges for refresh tokens.
+const AAD_SCOPE: &str = "https://management.azure.com/.default";
+
+/// The user name ACR expects along with a refresh token in Basic authentication.
+const REFRESH_TOKEN_USER: &str = "00000000-0000-0000-0000-000000000000";
+
+/// Lifetime assumed for tokens whose expiry cannot be read.
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(600);
+
+/// Tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Credentials for Azure Container Registry, exchanging an Azure AD token of a service principal
+/// for an ACR refresh token, and that for access tokens per scope.
+pub struct AcrCredentials {
+    registry: Url,
+    tenant_id: String,
+    client_id: String,
+    authority: Url,
+    client: UpstreamClient<Full<Bytes>>,
+    refresh_token: TokenCache,
+    access_tokens: ScopedTokenCache,
+}
+
+impl std::fmt::Debug for AcrCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AcrCredentials")
+            .field("registry", &self.registry)
+            .field("tenant_id", &self.tenant_id)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct AadTokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct ExchangeResponse {
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+struct AccessTokenResponse {
+    access_token: String,
+}
+
+impl AcrCredentials {
+    /// Prepares authentication for the registry at `registry` with the service principal in
+    /// `AZURE_TENANT_ID`, `AZURE_CLIENT_ID` and `AZURE_CLIENT_SECRET`, or `AZURE_CLIENT_SECRET_FILE`.
+    pub fn new(registry: &Url) -> Result<Self, BoxError> {
+        let var = |key: &str| env::var(key).map_err(|_| format!("{} is not set", key));
+        client_secret()?;
+        let authority = env::var("AZURE_AUTHORITY_HOST").unwrap_or_else(|_| DEFAULT_AUTHORITY.to_string());
+
+        let client = upstream_client();
+
+        Ok(Self {
+            registry: registry.clone(),
+            tenant_id: var("AZURE_TENANT_ID")?,
+            client_id: var("AZURE_CLIENT_ID")?,
+            authority: Url::parse(&authority)?,
+            client,
+            refresh_token: TokenCache::new(REFRESH_MARGIN),
+            access_tokens: ScopedTokenCache::new(REFRESH_MARGIN),
+        })
+    }
+
+    /// Returns the ACR refresh token, exchanging a new Azure AD token for it when the current
+    /// one is about to expire.
+    async fn refresh_token(&self) -> Result<String, BoxError> {
+        self.refresh_token.get(|| self.exchange()).await
+    }
+
+    /// Exchanges a new Azure AD token for an ACR refresh token, returned along with its expiry.
+    async fn exchange(&self) -> Result<(String, SystemTime), BoxError> {
+        let url = self.authority.join(&format!("/{}/oauth2/v2.0/token", self.tenant_id))?;
+        let client_secret = client_secret()?;
+        let aad: AadTokenResponse = self.post_form(&url, &[
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &client_secret),
+            ("scope", AAD_SCOPE),
+        ]).await?;
+
+        let exchange: ExchangeResponse = self.post_form(&self.registry.join("/oauth2/exchange")?, &[
+            ("grant_type", "access_token"),
+            ("service", self.service()),
+            ("tenant", &self.tenant_id),
+            ("access_token", &aad.access_token),
+        ]).await?;
+        debug!("exchanged an Azure AD token for an ACR refresh token of {}", self.service());
+
+        let expires = jwt_expiry(&exchange.refresh_token);
+        Ok((exchange.refresh_token, expires))
+    }
+
+    /// Requests an access token for `scope` with the refresh token, returned along with its expiry.
+    async fn access_token(&self, scope: &str, refresh_token: &str) -> Result<(String, SystemTime), BoxError> {
+        let response: AccessTokenResponse = self.post_form(&self.registry.join("/oauth2/token")?, &[
+            ("grant_type", "refresh_token"),
+            ("service", self.service()),
+            ("scope", scope),
+            ("refresh_token", refresh_token),
+        ]).await?;
+        let expires = jwt_expiry(&response.access_token);
+        Ok((response.access_token, expires))
+    }
+
+    fn service(&self) -> &str {
+        self.registry.host_str().unwrap_or_default()
+    }
+
+    async fn post_form<T: DeserializeOwned>(&self, url: &Url, params: &[(&str, &str)]) -> Result<T, BoxError> {
+        let body = form_urlencoded::Serializer::new(String::new()).extend_pairs(params).finish();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url.as_str())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Full::new(Bytes::from(body)))?;
+
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        let body = resp.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(format!("{} responded with {}: {}", url, status, String::from_utf8_lossy(&body)).into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+impl CredentialProvider for AcrCredentials {
+    /// Returns the `Authorization` header for a request needing `scope`: an access token for
+    /// the scope, or the refresh token itself for requests outside of any scope.
+    fn authorization<'a>(&'a self, scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(async move {
+            let refresh_token = self.refresh_token().await?;
+            let Some(scope) = scope else {
+                return Ok(format!("Basic {}", STANDARD.encode(format!("{}:{}", REFRESH_TOKEN_USER, refresh_token))));
+            };
+
+            let token = self.access_tokens.get(scope, || self.access_token(scope, &refresh_token)).await?;
+            Ok(format!("Bearer {}", token))
+        })
+    }
+
+    fn invalidate(&self, _scope: Option<&str>) {
+        self.refresh_token.clear();
+        self.access_tokens.clear();
+    }
+}
+
+/// Reads the client secret of the service principal, which may have been rotated since the last
+/// exchange.
+fn client_secret() -> Result<String, BoxError> {
+    secret_var("AZURE_CLIENT_SECRET")?.ok_or_else(|| "AZURE_CLIENT_SECRET is not set".into())
+}
+
+/// Reads the expiry of a token from the `exp` claim of the JWT it is.
+fn jwt_expiry(token: &str) -> SystemTime {
+    token.split('.').nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<serde_json::Value>(&payload).ok())
+        .and_then(|claims| claims.get("exp")?.as_u64())
+        .map(|exp| UNIX_EPOCH + Duration::from_secs(exp))
+        .unwrap_or_else(|| SystemTime::now() + DEFAULT_EXPIRY)
+}
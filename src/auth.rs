@@ -0,0 +1,108 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Mutex;
+
+use aws_lc_rs::digest;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use http::HeaderMap;
+use http::header::AUTHORIZATION;
+use tracing::warn;
+
+use crate::BoxError;
+
+/// Matches `name` against a pattern where `*` matches any sequence of characters, including `/`.
+pub(crate) fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Whether `name` is a repository name as defined by the distribution spec: `/`-separated
+/// components of lower-case letters and digits, joined by `.`, `_`, `__` or runs of `-`.
+///
+/// Names are checked before they are matched against patterns, as dot segments and escapes would
+/// otherwise be resolved to another repository once the name is put into a URL.
+pub(crate) fn valid_name(name: &str) -> bool {
+    let alphanumeric = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    name.split('/').all(|component| {
+        component.starts_with(alphanumeric)
+            && component.ends_with(alphanumeric)
+            && component.split(alphanumeric)
+                .filter(|separator| !separator.is_empty())
+                .all(|separator| matches!(separator, "." | "_" | "__") || separator.chars().all(|c| c == '-'))
+    })
+}
+
+/// The client a request was authenticated as, stored in the request extensions.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// Users loaded from an htpasswd file. Only bcrypt hashes are supported.
+#[derive(Debug)]
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    /// Digests of credentials that have already been verified, since docker sends them with
+    /// every request and bcrypt is deliberately slow.
+    verified: Mutex<HashSet<Vec<u8>>>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &Path) -> Result<Self, BoxError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        
+        let mut users = HashMap::new();
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let Some((user, hash)) = line.split_once(':') else {
+                warn!("Ignoring malformed line in {}", path.display());
+                continue;
+            };
+            if !hash.starts_with("$2") {
+                warn!("Ignoring user '{}' in {}: only bcrypt hashes are supported", user, path.display());
+                continue;
+            }
+            users.insert(user.to_string(), hash.to_string());
+        }
+        
+        Ok(Self { users, verified: Mutex::new(HashSet::new()) })
+    }
+
+    /// Verifies the Basic credentials in `headers`, returning the authenticated user.
+    pub async fn authenticate(&self, headers: &HeaderMap) -> Option<Identity> {
+        let encoded = headers.get(AUTHORIZATION)?.to_str().ok()?.strip_prefix("Basic ")?;
+        let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let hash = self.users.get(user)?;
+        
+        let key = digest::digest(&digest::SHA256, format!("{}\n{}", decoded, hash).as_bytes()).as_ref().to_vec();
+        if self.verified.lock().unwrap().contains(&key) {
+            return Some(Identity(user.to_string()));
+        }
+        
+        let (password, hash) = (password.to_string(), hash.clone());
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        if !valid {
+            return None;
+        }
+        
+        self.verified.lock().unwrap().insert(key);
+        Some(Identity(user.to_string()))
+    }
+}
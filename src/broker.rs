@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use http::Request;
+use http::header::AUTHORIZATION;
+use http_body_util::{BodyExt, Empty};
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use crate::{upstream_client, BoxError, UpstreamClient};
+use crate::credentials::{BoxFuture, CredentialProvider, ScopedTokenCache};
+
+/// Lifetime assumed for tokens without `expires_in`, as specified by the token authentication spec.
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(60);
+
+/// Tokens are renewed this long before they expire, so that they do not expire in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// Obtains bearer tokens from the token endpoint of an upstream registry on behalf of clients,
+/// caching them per scope until they expire.
+pub struct TokenBroker {
+    endpoint: Url,
+    service: Option<String>,
+    /// Credentials presented to the token endpoint. Tokens are requested anonymously if unset.
+    auth: Option<Arc<dyn CredentialProvider>>,
+    client: UpstreamClient<Empty<Bytes>>,
+    tokens: ScopedTokenCache,
+}
+
+impl std::fmt::Debug for TokenBroker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenBroker")
+            .field("endpoint", &self.endpoint)
+            .field("service", &self.service)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    token: String,
+    #[serde(default)]
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl TokenBroker {
+    pub fn new(endpoint: Url, service: Option<String>, auth: Option<Arc<dyn CredentialProvider>>) -> Self {
+        let client = upstream_client();
+
+        Self { endpoint, service, auth, client, tokens: ScopedTokenCache::new(EXPIRY_MARGIN) }
+    }
+
+    /// Returns a token granting `scope`, requesting a new one once the cached token is about
+    /// to expire.
+    async fn token(&self, scope: Option<&str>) -> Result<String, BoxError> {
+        self.tokens.get(scope.unwrap_or_default(), || self.request_token(scope)).await
+    }
+
+    /// Requests a token granting `scope` from the token endpoint, returning it along with its
+    /// expiry.
+    async fn request_token(&self, scope: Option<&str>) -> Result<(String, SystemTime), BoxError> {
+        let mut url = self.endpoint.clone();
+        if let Some(service) = &self.service {
+            url.query_pairs_mut().append_pair("service", service);
+        }
+        if let Some(scope) = scope {
+            url.query_pairs_mut().append_pair("scope", scope);
+        }
+        debug!("requesting upstream token for scope '{}'", scope.unwrap_or_default());
+
+        let mut req = Request::builder().uri(url.as_str());
+        if let Some(auth) = &self.auth {
+            req = req.header(AUTHORIZATION, auth.authorization(None).await?);
+        }
+        let resp = self.client.request(req.body(Empty::new())?).await?;
+        if !resp.status().is_success() {
+            return Err(format!("{} responded with {}", self.endpoint, resp.status()).into());
+        }
+
+        let body = resp.into_body().collect().await?.to_bytes();
+        let response: TokenResponse = serde_json::from_slice(&body)?;
+        let token = if response.token.is_empty() { response.access_token } else { response.token };
+        if token.is_empty() {
+            return Err(format!("{} did not return a token", self.endpoint).into());
+        }
+
+        let lifetime = response.expires_in.map(Duration::from_secs).unwrap_or(DEFAULT_EXPIRY);
+        Ok((token, SystemTime::now() + lifetime))
+    }
+}
+
+impl CredentialProvider for TokenBroker {
+    fn authorization<'a>(&'a self, scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(async move { Ok(format!("Bearer {}", self.token(scope).await?)) })
+    }
+
+    /// Forgets the token for `scope`, and the credentials it was obtained with, which may have
+    /// been revoked as well.
+    fn invalidate(&self, scope: Option<&str>) {
+        self.tokens.remove(scope.unwrap_or_default());
+        if let Some(auth) = &self.auth {
+            auth.invalidate(None);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use aws_lc_rs::digest;
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Body, Frame, SizeHint};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info};
+use url::Url;
+
+use crate::Registry;
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, io::Error>;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Chunks of a blob that may be waiting to be written to disk. A blob whose writes fall further
+/// behind the client is not cached, rather than buffered in memory or slowing the client down.
+const TEE_CAPACITY: usize = 256;
+
+/// Content-addressed blob storage on local disk.
+///
+/// Blobs are stored under `<root>/blobs/<registry>/sha256/<hex>` and only become visible once
+/// their content has been verified against the digest they were requested by. Each registry has
+/// its own directory, so that a blob is never served through a registry it was not pulled from.
+///
+/// The repositories a blob is known to be in are recorded under `<root>/blobs/<registry>/seen`,
+/// as a blob pulled from one repository must not be served through another that lacks it.
+#[derive(Debug, Clone)]
+pub struct BlobCache {
+    root: PathBuf,
+}
+
+impl BlobCache {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join("blobs"))?;
+        std::fs::create_dir_all(root.join("tmp"))?;
+        Ok(Self { root })
+    }
+
+    fn namespace(&self, registry: &Registry) -> PathBuf {
+        let key = format!("{}\n{}", registry.endpoint, registry.repo_prefix);
+        let namespace = to_hex(&digest::digest(&digest::SHA256, key.as_bytes()).as_ref()[..8]);
+        self.root.join("blobs").join(namespace)
+    }
+
+    fn path(&self, registry: &Registry, digest: &str) -> Option<PathBuf> {
+        let hex = sha256_hex(digest)?;
+        Some(self.namespace(registry).join("sha256").join(hex))
+    }
+
+    fn marker(&self, registry: &Registry, repository: &str, digest: &str) -> PathBuf {
+        let key = format!("{}\n{}", repository, digest);
+        let hash = digest::digest(&digest::SHA256, key.as_bytes());
+        self.namespace(registry).join("seen").join(to_hex(hash.as_ref()))
+    }
+
+    /// Whether the blob has been found in `repository` of `registry` before.
+    pub async fn seen_in(&self, registry: &Registry, repository: &str, digest: &str) -> bool {
+        fs::metadata(self.marker(registry, repository, digest)).await.is_ok()
+    }
+
+    /// Records that the upstream has the blob in `repository` of `registry`.
+    pub async fn record(&self, registry: &Registry, repository: &str, digest: &str) {
+        let marker = self.marker(registry, repository, digest);
+        let result = match fs::create_dir_all(self.namespace(registry).join("seen")).await {
+            Ok(()) => fs::write(&marker, b"").await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Unable to record cached blob {} in {}: {}", digest, repository, e);
+        }
+    }
+
+    /// Opens a blob cached from `registry`, returning its content as a body along with its length.
+    pub async fn open(&self, registry: &Registry, digest: &str) -> Option<(BoxBody, u64)> {
+        let path = self.path(registry, digest)?;
+        let file = File::open(&path).await.ok()?;
+        let len = file.metadata().await.ok()?.len();
+        debug!("blob cache hit: {}", digest);
+        
+        let stream = ReaderStream::new(file).map_ok(Frame::data);
+        Some((StreamBody::new(stream).boxed(), len))
+    }
+
+    /// Wraps an upstream blob body so that its content is written to the cache as it is
+    /// streamed to the client.
+    pub fn tee(&self, registry: &Registry, digest: &str, body: BoxBody) -> BoxBody {
+        let Some(path) = self.path(registry, digest) else {
+            return body;
+        };
+        let temp = self.root.join("tmp").join(temp_name(&path));
+        
+        let (tx, rx) = mpsc::channel(TEE_CAPACITY);
+        tokio::spawn(write_blob(digest.to_string(), temp, path, rx));
+        
+        TeeBody { inner: body, tx: Some(tx) }.boxed()
+    }
+}
+
+/// Manifest storage on local disk, keyed by upstream URL and negotiated media types.
+///
+/// Manifests requested by digest are immutable and kept forever, while manifests requested by
+/// tag are only served until `ttl` has elapsed. A zero `ttl` disables caching tags.
+#[derive(Debug, Clone)]
+pub struct ManifestCache {
+    root: PathBuf,
+    ttl: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedManifest {
+    pub content_type: Option<String>,
+    pub digest: Option<String>,
+    pub body: Bytes,
+}
+
+impl ManifestCache {
+    pub fn new(root: impl Into<PathBuf>, ttl: Duration) -> io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join("manifests"))?;
+        std::fs::create_dir_all(root.join("tmp"))?;
+        Ok(Self { root, ttl })
+    }
+
+    /// The directory holding the variants of the manifest at `url`, one per `Accept` header.
+    fn dir(&self, url: &Url) -> PathBuf {
+        let hash = digest::digest(&digest::SHA256, url.as_str().as_bytes());
+        self.root.join("manifests").join(to_hex(hash.as_ref()))
+    }
+
+    fn path(&self, url: &Url, accept: &str) -> PathBuf {
+        let hash = digest::digest(&digest::SHA256, accept.as_bytes());
+        self.dir(url).join(to_hex(hash.as_ref()))
+    }
+
+    fn enabled(&self, reference: &str) -> bool {
+        is_digest(reference) || !self.ttl.is_zero()
+    }
+
+    pub async fn get(&self, url: &Url, accept: &str, reference: &str) -> Option<CachedManifest> {
+        if !self.enabled(reference) {
+            return None;
+        }
+        let path = self.path(url, accept);
+        
+        if !is_digest(reference) {
+            let modified = fs::metadata(&path).await.ok()?.modified().ok()?;
+            if modified.elapsed().map_or(true, |age| age > self.ttl) {
+                return None;
+            }
+        }
+        
+        let contents = fs::read(&path).await.ok()?;
+        let manifest = CachedManifest::decode(Bytes::from(contents))?;
+        debug!("manifest cache hit: {}", url);
+        Some(manifest)
+    }
+
+    pub async fn put(&self, url: &Url, accept: &str, reference: &str, manifest: &CachedManifest) {
+        if !self.enabled(reference) {
+            return;
+        }
+        if is_digest(reference) {
+            let actual = format!("sha256:{}", to_hex(digest::digest(&digest::SHA256, &manifest.body).as_ref()));
+            if actual != reference {
+                debug!("not caching manifest with mismatched digest: {}", url);
+                return;
+            }
+        }
+        
+        let path = self.path(url, accept);
+        let temp = self.root.join("tmp").join(temp_name(&path));
+        let result = match fs::write(&temp, manifest.encode()).await {
+            Ok(()) => match fs::create_dir_all(self.dir(url)).await {
+                Ok(()) => fs::rename(&temp, &path).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => debug!("cached manifest: {}", url),
+            Err(e) => {
+                error!("Unable to store manifest cache file {}: {}", path.display(), e);
+                let _ = fs::remove_file(&temp).await;
+            }
+        }
+    }
+
+    /// Removes every cached variant of the manifest at `url`, after it was pushed or deleted.
+    pub async fn evict(&self, url: &Url) {
+        match fs::remove_dir_all(self.dir(url)).await {
+            Ok(()) => debug!("evicted cached manifest: {}", url),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Unable to evict cached manifest {}: {}", url, e),
+        }
+    }
+}
+
+impl CachedManifest {
+    /// Serializes the manifest as header lines followed by an empty line and the manifest body.
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.body.len() + 128);
+        if let Some(content_type) = &self.content_type {
+            out.extend_from_slice(format!("Content-Type: {}\n", content_type).as_bytes());
+        }
+        if let Some(digest) = &self.digest {
+            out.extend_from_slice(format!("Docker-Content-Digest: {}\n", digest).as_bytes());
+        }
+        out.push(b'\n');
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    fn decode(contents: Bytes) -> Option<Self> {
+        let end = if contents.starts_with(b"\n") {
+            0
+        } else {
+            contents.windows(2).position(|w| w == b"\n\n")? + 1
+        };
+        let head = std::str::from_utf8(&contents[..end]).ok()?;
+        
+        let mut manifest = Self { content_type: None, digest: None, body: contents.slice(end + 1..) };
+        for line in head.lines() {
+            match line.split_once(": ") {
+                Some(("Content-Type", value)) => manifest.content_type = Some(value.to_string()),
+                Some(("Docker-Content-Digest", value)) => manifest.digest = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(manifest)
+    }
+}
+
+fn is_digest(reference: &str) -> bool {
+    reference.contains(':')
+}
+
+fn temp_name(path: &Path) -> String {
+    format!(
+        "{}.{}.{}",
+        path.file_name().and_then(|n| n.to_str()).unwrap_or_default(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+    )
+}
+
+/// Returns the hex part of a `sha256:` digest, rejecting anything that is not a valid one.
+fn sha256_hex(digest: &str) -> Option<&str> {
+    let hex = digest.strip_prefix("sha256:")?;
+    if hex.len() == 64 && hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        Some(hex)
+    } else {
+        None
+    }
+}
+
+/// Writes chunks received from a [`TeeBody`] to `temp`, and moves the file into place once the
+/// body has ended and its content matches `digest`. An incomplete or corrupt blob is discarded.
+async fn write_blob(digest: String, temp: PathBuf, path: PathBuf, mut rx: mpsc::Receiver<Bytes>) {
+    let mut file = match File::create(&temp).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Unable to create blob cache file {}: {}", temp.display(), e);
+            return;
+        }
+    };
+    
+    let mut context = digest::Context::new(&digest::SHA256);
+    let mut failed = false;
+    while let Some(chunk) = rx.recv().await {
+        context.update(&chunk);
+        if let Err(e) = file.write_all(&chunk).await {
+            error!("Unable to write blob cache file {}: {}", temp.display(), e);
+            failed = true;
+            break;
+        }
+    }
+    
+    let actual = format!("sha256:{}", to_hex(context.finish().as_ref()));
+    if !failed && file.flush().await.is_ok() && actual == digest {
+        drop(file);
+        let parent = path.parent().unwrap_or(&path);
+        let stored = match fs::create_dir_all(parent).await {
+            Ok(()) => fs::rename(&temp, &path).await,
+            Err(e) => Err(e),
+        };
+        match stored {
+            Ok(()) => {
+                info!("cached blob: {}", digest);
+                return;
+            }
+            Err(e) => error!("Unable to store blob cache file {}: {}", path.display(), e),
+        }
+    } else if !failed {
+        debug!("discarding incomplete blob: {}", digest);
+    }
+    let _ = fs::remove_file(&temp).await;
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// A body that forwards every data frame of `inner` to a cache writer.
+struct TeeBody {
+    inner: BoxBody,
+    tx: Option<mpsc::Sender<Bytes>>,
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(tx)) = (frame.data_ref(), &self.tx) {
+                    match tx.try_send(data.clone()) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full(_)) => {
+                            debug!("blob cache writes fell behind, not caching the blob");
+                            self.tx = None;
+                        }
+                        Err(TrySendError::Closed(_)) => self.tx = None,
+                    }
+                }
+            }
+            // Dropping the sender finishes the write. A body cut short by an error fails
+            // digest verification and is discarded by the writer.
+            Poll::Ready(Some(Err(_))) | Poll::Ready(None) => self.tx = None,
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::BoxError;
+
+/// Configuration loaded from an optional TOML file, with environment variables overriding
+/// individual keys.
+///
+/// ```toml
+/// hostname = "cr.example.com"
+/// drain_timeout = 30
+/// max_body_size = 1073741824
+///
+/// [bind]
+/// host = "0.0.0.0"
+/// port = 8080
+///
+/// [tls]
+/// cert = "/etc/conex/tls.crt"
+/// key = "/etc/conex/tls.key"
+///
+/// [auth]
+/// htpasswd = "/etc/conex/htpasswd"
+/// token_secret_file = "/etc/conex/secrets/token-secret"
+///
+/// [[auth.grants]]
+/// users = ["alice"]
+/// repositories = ["myteam/*"]
+/// actions = ["pull", "push"]
+///
+/// [rate_limit]
+/// manifests = { rate = 5, burst = 20 }
+/// blobs = { rate = 50 }
+///
+/// [cache]
+/// dir = "/var/cache/conex"
+/// manifest_ttl = 300
+///
+/// [registry]
+/// host = "https://index.docker.io"
+/// prefix = "library"
+/// deny = ["*/internal-*"]
+///
+/// [routes.gar]
+/// host = "https://asia-northeast3-docker.pkg.dev"
+/// prefix = "my-project/my-repo"
+/// google_application_credentials = "/path/to/key.json"
+/// allow = ["myteam/*"]
+/// token_broker = true
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub hostname: Option<String>,
+    /// Seconds to wait for in-flight requests to finish on shutdown.
+    pub drain_timeout: Option<u64>,
+    /// Largest request body, in bytes, forwarded upstream. Unlimited when unset.
+    pub max_body_size: Option<u64>,
+    pub bind: BindConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub rate_limit: RateLimitConfig,
+    pub cache: CacheConfig,
+    /// The registry serving repositories that are not matched by any route.
+    pub registry: Option<RegistryConfig>,
+    pub routes: BTreeMap<String, RegistryConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindConfig {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// A PEM certificate chain and private key for serving HTTPS directly.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+/// Authentication of clients accessing conex.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// An htpasswd file with bcrypt hashed passwords.
+    pub htpasswd: Option<PathBuf>,
+    /// Secret for signing tokens issued by conex. Setting it makes conex its own token server.
+    pub token_secret: Option<String>,
+    /// A file holding the token secret instead. Configuration is reloaded when it changes.
+    pub token_secret_file: Option<PathBuf>,
+    /// Seconds for which issued tokens are valid.
+    pub token_ttl: Option<u64>,
+    /// Access granted to clients in issued tokens. Without any grant, every client is granted
+    /// what it requests.
+    pub grants: Vec<GrantConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrantConfig {
+    /// Users the grant applies to, where `*` also matches anonymous clients.
+    #[serde(default = "wildcard")]
+    pub users: Vec<String>,
+    /// Repository name patterns, where `*` matches any sequence of characters.
+    #[serde(default = "wildcard")]
+    pub repositories: Vec<String>,
+    #[serde(default = "pull")]
+    pub actions: Vec<String>,
+}
+
+fn wildcard() -> Vec<String> {
+    vec!["*".to_string()]
+}
+
+fn pull() -> Vec<String> {
+    vec!["pull".to_string()]
+}
+
+/// Rate limits per client, keyed by the authenticated user or the client address.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub manifests: Option<BucketConfig>,
+    pub blobs: Option<BucketConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    /// Requests per second.
+    pub rate: f64,
+    /// Requests that may be made at once, defaulting to `rate`.
+    pub burst: Option<f64>,
+}
+
+impl BucketConfig {
+    pub fn burst(&self) -> f64 {
+        self.burst.unwrap_or(self.rate).max(1.0)
+    }
+
+    /// Checks that the rate and burst are positive numbers, as the limiter divides by the rate.
+    /// `name` is the setting reported in the error.
+    pub fn validate(&self, name: &str) -> Result<(), BoxError> {
+        if !(self.rate.is_finite() && self.rate > 0.0) {
+            return Err(format!("{} must have a positive rate, not {}", name, self.rate).into());
+        }
+        if let Some(burst) = self.burst.filter(|burst| !(burst.is_finite() && *burst > 0.0)) {
+            return Err(format!("{} must have a positive burst, not {}", name, burst).into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub dir: Option<PathBuf>,
+    /// Seconds for which manifests pulled by tag are cached.
+    pub manifest_ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    pub host: Option<String>,
+    pub prefix: Option<String>,
+    pub auth_header: Option<String>,
+    /// A file holding the `Authorization` header instead, read again whenever it changes.
+    pub auth_header_file: Option<PathBuf>,
+    pub google_application_credentials: Option<PathBuf>,
+    /// Whether to authenticate to Amazon ECR with the AWS credentials of the environment.
+    pub aws_ecr: bool,
+    /// The ECR API endpoint, overriding the regional endpoint.
+    pub aws_ecr_endpoint: Option<String>,
+    /// Whether to authenticate to Azure Container Registry with the service principal of the
+    /// environment.
+    pub azure_acr: bool,
+    /// A docker `config.json`, or the directory containing it, to read credentials from.
+    pub docker_config: Option<PathBuf>,
+    /// Repository patterns that may be pulled, relative to the prefix.
+    pub allow: Vec<String>,
+    /// Repository patterns that may not be pulled, taking precedence over `allow`.
+    pub deny: Vec<String>,
+    /// Whether conex obtains upstream tokens itself instead of leaving it to clients.
+    pub token_broker: bool,
+}
+
+impl Config {
+    /// Reads the configuration file at `path`, if any, and applies environment overrides.
+    pub fn load(path: Option<&Path>) -> Result<Self, BoxError> {
+        let mut config = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Files holding secrets that are only read when the state is built, and are therefore
+    /// watched for changes along with the configuration file.
+    pub fn secret_files(&self) -> Vec<PathBuf> {
+        let google_keys = self.registry.iter()
+            .chain(self.routes.values())
+            .filter_map(|registry| registry.google_application_credentials.as_ref());
+        self.auth.htpasswd.iter().chain(&self.auth.token_secret_file).chain(google_keys).cloned().collect()
+    }
+
+    fn apply_env(&mut self) -> Result<(), BoxError> {
+        // Container runtimes always set `HOSTNAME` to the container name, so it only applies
+        // when the file does not set a hostname. `CONEX_HOSTNAME` overrides either.
+        if let Ok(hostname) = env::var("CONEX_HOSTNAME") {
+            self.hostname = Some(hostname);
+        } else if self.hostname.is_none() {
+            self.hostname = env::var("HOSTNAME").ok();
+        }
+        if let Ok(timeout) = env::var("DRAIN_TIMEOUT") {
+            self.drain_timeout = Some(timeout.parse().map_err(|e| format!("DRAIN_TIMEOUT is not a valid number of seconds: {}", e))?);
+        }
+        if let Ok(size) = env::var("MAX_BODY_SIZE") {
+            self.max_body_size = Some(size.parse().map_err(|e| format!("MAX_BODY_SIZE is not a valid number of bytes: {}", e))?);
+        }
+        if let Ok(host) = env::var("BIND_HOST") {
+            self.bind.host = Some(host);
+        }
+        if let Ok(port) = env::var("BIND_PORT") {
+            self.bind.port = Some(port.parse().map_err(|e| format!("BIND_PORT is not a valid port: {}", e))?);
+        }
+        if let Ok(cert) = env::var("TLS_CERT") {
+            self.tls.cert = Some(cert.into());
+        }
+        if let Ok(key) = env::var("TLS_KEY") {
+            self.tls.key = Some(key.into());
+        }
+        if let Ok(htpasswd) = env::var("AUTH_HTPASSWD") {
+            self.auth.htpasswd = Some(htpasswd.into());
+        }
+        if let Ok(secret) = env::var("AUTH_TOKEN_SECRET") {
+            self.auth.token_secret = Some(secret);
+            self.auth.token_secret_file = None;
+        }
+        if let Ok(path) = env::var("AUTH_TOKEN_SECRET_FILE") {
+            self.auth.token_secret_file = Some(path.into());
+            self.auth.token_secret = None;
+        }
+        if let Ok(ttl) = env::var("AUTH_TOKEN_TTL") {
+            self.auth.token_ttl = Some(ttl.parse().map_err(|e| format!("AUTH_TOKEN_TTL is not a valid number of seconds: {}", e))?);
+        }
+        bucket_env(&mut self.rate_limit.manifests, "RATE_LIMIT_MANIFESTS")?;
+        bucket_env(&mut self.rate_limit.blobs, "RATE_LIMIT_BLOBS")?;
+        if let Ok(dir) = env::var("CACHE_DIR") {
+            self.cache.dir = Some(dir.into());
+        }
+        if let Ok(ttl) = env::var("MANIFEST_CACHE_TTL") {
+            self.cache.manifest_ttl = Some(ttl.parse().map_err(|e| format!("MANIFEST_CACHE_TTL is not a valid number of seconds: {}", e))?);
+        }
+
+        if let Ok(routes) = env::var("REGISTRY_ROUTES") {
+            for route in split_list(&routes) {
+                self.routes.entry(route).or_default();
+            }
+        }
+        
+        // Without any route, the environment describes the single registry being proxied.
+        let has_env = env::var("REGISTRY_HOST").is_ok() || env::var("REGISTRY_PREFIX").is_ok();
+        if self.registry.is_none() && (self.routes.is_empty() || has_env) {
+            self.registry = Some(RegistryConfig::default());
+        }
+        if let Some(registry) = &mut self.registry {
+            registry.apply_env(None)?;
+        }
+        for (route, registry) in &mut self.routes {
+            registry.apply_env(Some(route))?;
+        }
+        Ok(())
+    }
+}
+
+impl RegistryConfig {
+    fn apply_env(&mut self, route: Option<&str>) -> Result<(), BoxError> {
+        if let Ok(host) = env::var(route_key(route, "REGISTRY_HOST")) {
+            self.host = Some(host);
+        }
+        if let Ok(prefix) = env::var(route_key(route, "REGISTRY_PREFIX")) {
+            self.prefix = Some(prefix);
+        }
+        if let Ok(key) = env::var(route_key(route, "GOOGLE_APPLICATION_CREDENTIALS")) {
+            self.google_application_credentials = Some(key.into());
+        }
+        let key = route_key(route, "AWS_ECR");
+        if let Ok(ecr) = env::var(&key) {
+            self.aws_ecr = ecr.parse().map_err(|_| format!("{} must be 'true' or 'false'", key))?;
+        }
+        if let Ok(endpoint) = env::var(route_key(route, "AWS_ECR_ENDPOINT")) {
+            self.aws_ecr_endpoint = Some(endpoint);
+        }
+        let key = route_key(route, "AZURE_ACR");
+        if let Ok(acr) = env::var(&key) {
+            self.azure_acr = acr.parse().map_err(|_| format!("{} must be 'true' or 'false'", key))?;
+        }
+        if let Ok(path) = env::var(route_key(route, "DOCKER_CONFIG")) {
+            self.docker_config = Some(path.into());
+        }
+        if let Ok(header) = env::var(route_key(route, "AUTH_HEADER")) {
+            self.auth_header = Some(header);
+            self.auth_header_file = None;
+        }
+        if let Ok(path) = env::var(route_key(route, "AUTH_HEADER_FILE")) {
+            self.auth_header_file = Some(path.into());
+            self.auth_header = None;
+        }
+        if let Ok(allow) = env::var(route_key(route, "REGISTRY_ALLOW")) {
+            self.allow = split_list(&allow);
+        }
+        if let Ok(deny) = env::var(route_key(route, "REGISTRY_DENY")) {
+            self.deny = split_list(&deny);
+        }
+        let key = route_key(route, "TOKEN_BROKER");
+        if let Ok(broker) = env::var(&key) {
+            self.token_broker = broker.parse().map_err(|_| format!("{} must be 'true' or 'false'", key))?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a rate limit given as `<rate>` or `<rate>/<burst>`.
+fn bucket_env(bucket: &mut Option<BucketConfig>, key: &str) -> Result<(), BoxError> {
+    let Ok(value) = env::var(key) else {
+        return Ok(());
+    };
+    let (rate, burst) = match value.split_once('/') {
+        Some((rate, burst)) => (rate, Some(burst)),
+        None => (value.as_str(), None),
+    };
+    let invalid = |e: std::num::ParseFloatError| format!("{} is not a valid rate limit: {}", key, e);
+    
+    let rate: f64 = rate.trim().parse().map_err(invalid)?;
+    let burst = burst.map(|b| b.trim().parse()).transpose().map_err(invalid)?;
+    let config = BucketConfig { rate, burst };
+    config.validate(key)?;
+    *bucket = Some(config);
+    Ok(())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(String::from).collect()
+}
+
+/// Environment variables of a named route are prefixed with the upper-cased route name,
+/// e.g. `HUB_REGISTRY_HOST` for the route `hub`.
+pub(crate) fn route_key(route: Option<&str>, key: &str) -> String {
+    match route {
+        Some(route) => format!("{}_{}", route.to_uppercase().replace('-', "_"), key),
+        None => key.to_string(),
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::env;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+use crate::BoxError;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A source of the credentials presented to an upstream registry.
+///
+/// Providers of short-lived credentials cache them and refresh them ahead of their expiry, so
+/// that `authorization` is cheap to call for every request.
+pub trait CredentialProvider: Send + Sync + std::fmt::Debug {
+    /// Returns the `Authorization` header value for a request needing the token `scope`.
+    fn authorization<'a>(&'a self, scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>>;
+
+    /// Discards cached credentials after the upstream rejected them for `scope`, so that the
+    /// next call obtains new ones.
+    fn invalidate(&self, _scope: Option<&str>) {}
+}
+
+/// A fixed `Authorization` header value.
+pub struct StaticCredentials(String);
+
+impl StaticCredentials {
+    pub fn new(header: String) -> Self {
+        Self(header)
+    }
+}
+
+impl std::fmt::Debug for StaticCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticCredentials").finish_non_exhaustive()
+    }
+}
+
+impl CredentialProvider for StaticCredentials {
+    fn authorization<'a>(&'a self, _scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(async move { Ok(self.0.clone()) })
+    }
+}
+
+/// An `Authorization` header value kept in a file, read again whenever the file changes.
+pub struct FileCredentials {
+    path: PathBuf,
+    cached: Mutex<(String, Option<SystemTime>)>,
+}
+
+impl FileCredentials {
+    pub fn load(path: &Path) -> Result<Self, BoxError> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let header = read_secret(path)?;
+        Ok(Self { path: path.to_path_buf(), cached: Mutex::new((header, modified)) })
+    }
+}
+
+impl std::fmt::Debug for FileCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FileCredentials")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn authorization<'a>(&'a self, _scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(async move {
+            let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+            let mut cached = self.cached.lock().unwrap();
+            if modified.is_none() || modified == cached.1 {
+                return Ok(cached.0.clone());
+            }
+
+            // The previous credentials stay in use if the file is being replaced.
+            match read_secret(&self.path) {
+                Ok(header) => {
+                    info!("Credentials in {} changed", self.path.display());
+                    *cached = (header, modified);
+                }
+                Err(e) => warn!("{}, keeping the previous credentials", e),
+            }
+            Ok(cached.0.clone())
+        })
+    }
+}
+
+/// Reads a secret kept in a file, such as a mounted Kubernetes secret, ignoring surrounding
+/// whitespace.
+pub(crate) fn read_secret(path: &Path) -> Result<String, BoxError> {
+    let secret = std::fs::read_to_string(path)
+        .map_err(|e| format!("{} cannot be read: {}", path.display(), e))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        return Err(format!("{} is empty", path.display()).into());
+    }
+    Ok(secret.to_string())
+}
+
+/// Reads the secret in the environment variable `key`, or in the file named by `<key>_FILE`.
+/// The file is read on every call, so that rotated secrets are picked up.
+pub(crate) fn secret_var(key: &str) -> Result<Option<String>, BoxError> {
+    match env::var_os(format!("{}_FILE", key)) {
+        Some(path) => read_secret(Path::new(&path))
+            .map(Some)
+            .map_err(|e| format!("{}_FILE is set, but {}", key, e).into()),
+        None => Ok(env::var(key).ok()),
+    }
+}
+
+/// Longest time a token refresh may take before callers waiting for it give up.
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Caches a short-lived token for token-based providers, obtaining a new one `margin` before
+/// the current one expires.
+pub struct TokenCache {
+    margin: Duration,
+    token: tokio::sync::Mutex<Option<(String, SystemTime)>>,
+}
+
+impl TokenCache {
+    pub fn new(margin: Duration) -> Self {
+        Self { margin, token: tokio::sync::Mutex::new(None) }
+    }
+
+    /// Returns the cached token, or the token `refresh` returns along with its expiry. Concurrent
+    /// callers wait for a single refresh, which is abandoned if it does not finish in time.
+    pub async fn get<F, Fut>(&self, refresh: F) -> Result<String, BoxError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, SystemTime), BoxError>>,
+    {
+        let mut token = self.token.lock().await;
+        if let Some((value, refresh_at)) = token.as_ref() {
+            if *refresh_at > SystemTime::now() {
+                return Ok(value.clone());
+            }
+        }
+
+        let (value, expires) = tokio::time::timeout(REFRESH_TIMEOUT, refresh()).await
+            .map_err(|_| format!("Obtaining a token timed out after {:?}", REFRESH_TIMEOUT))??;
+        *token = Some((value.clone(), expires.checked_sub(self.margin).unwrap_or(expires)));
+        Ok(value)
+    }
+
+    /// Whether the cached token is due for a refresh that nobody has started, so that the cache
+    /// can be dropped by owners keeping one per scope.
+    pub(crate) fn is_stale(&self) -> bool {
+        self.token.try_lock().is_ok_and(|token| {
+            token.as_ref().is_some_and(|(_, refresh_at)| *refresh_at <= SystemTime::now())
+        })
+    }
+
+    /// Discards the cached token. A refresh in progress is left to finish.
+    pub fn clear(&self) {
+        if let Ok(mut token) = self.token.try_lock() {
+            *token = None;
+        }
+    }
+}
+
+/// Caches a short-lived token per scope, for providers whose tokens are granted per repository.
+/// Each scope has its own `TokenCache`, so that concurrent requests for a scope wait for a
+/// single refresh.
+pub(crate) struct ScopedTokenCache {
+    margin: Duration,
+    caches: Mutex<HashMap<String, Arc<TokenCache>>>,
+}
+
+impl ScopedTokenCache {
+    pub(crate) fn new(margin: Duration) -> Self {
+        Self { margin, caches: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns the cached token for `scope`, or the token `refresh` returns along with its expiry.
+    pub(crate) async fn get<F, Fut>(&self, scope: &str, refresh: F) -> Result<String, BoxError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(String, SystemTime), BoxError>>,
+    {
+        let cache = {
+            let mut caches = self.caches.lock().unwrap();
+            if !caches.contains_key(scope) {
+                caches.retain(|_, cache| !cache.is_stale());
+            }
+            caches.entry(scope.to_string())
+                .or_insert_with(|| Arc::new(TokenCache::new(self.margin)))
+                .clone()
+        };
+
+        let token = cache.get(refresh).await;
+        if token.is_err() {
+            // Scopes that cannot be obtained are not kept around.
+            let mut caches = self.caches.lock().unwrap();
+            if caches.get(scope).is_some_and(|cached| Arc::ptr_eq(cached, &cache)) {
+                caches.remove(scope);
+            }
+        }
+        token
+    }
+
+    /// Discards the cached token for `scope`.
+    pub(crate) fn remove(&self, scope: &str) {
+        self.caches.lock().unwrap().remove(scope);
+    }
+
+    /// Discards the cached tokens of every scope.
+    pub(crate) fn clear(&self) {
+        self.caches.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::debug;
+use url::Url;
+
+use crate::BoxError;
+use crate::credentials::{BoxFuture, CredentialProvider, TokenCache};
+
+/// The key Docker Hub credentials are stored under.
+const DOCKER_HUB: &str = "https://index.docker.io/v1/";
+
+/// Credentials are looked up again after this long, picking up new logins and tokens renewed
+/// by credential helpers.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Credentials for a registry from a docker `config.json`, as written by `docker login`.
+pub struct DockerCredentials {
+    path: PathBuf,
+    host: String,
+    cached: TokenCache,
+}
+
+impl std::fmt::Debug for DockerCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DockerCredentials")
+            .field("path", &self.path)
+            .field("host", &self.host)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+    creds_store: Option<String>,
+    #[serde(default)]
+    cred_helpers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+    username: String,
+    secret: String,
+}
+
+/// Where the credentials of a registry are kept.
+enum Source {
+    /// The base64 `user:password` stored in the config itself.
+    Auth(String),
+    /// A `docker-credential-*` helper, along with the server URL to ask it for.
+    Helper(String, String),
+}
+
+impl DockerCredentials {
+    /// Prepares the credentials for the registry at `endpoint` from `path`, either a
+    /// `config.json` or the directory containing it.
+    pub fn load(path: &Path, endpoint: &Url) -> Result<Self, BoxError> {
+        let path = if path.is_dir() { path.join("config.json") } else { path.to_path_buf() };
+        let host = registry_host(endpoint);
+        if DockerConfig::read(&path)?.source(&host).is_none() {
+            return Err(format!("{} has no credentials for {}", path.display(), host).into());
+        }
+        Ok(Self { path, host, cached: TokenCache::new(Duration::ZERO) })
+    }
+
+    /// Looks the credentials up in the config, running its credential helper if it has one.
+    async fn lookup(&self) -> Result<String, BoxError> {
+        let source = DockerConfig::read(&self.path)?.source(&self.host)
+            .ok_or_else(|| format!("{} has no credentials for {}", self.path.display(), self.host))?;
+        let auth = match source {
+            Source::Auth(auth) => auth,
+            Source::Helper(helper, server) => {
+                let credentials = run_helper(&helper, &server).await?;
+                STANDARD.encode(format!("{}:{}", credentials.username, credentials.secret))
+            }
+        };
+        Ok(format!("Basic {}", auth))
+    }
+}
+
+impl CredentialProvider for DockerCredentials {
+    /// Returns the `Authorization` header, looking the credentials up again once in a while.
+    fn authorization<'a>(&'a self, _scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(self.cached.get(|| async {
+            Ok((self.lookup().await?, SystemTime::now() + LOOKUP_INTERVAL))
+        }))
+    }
+
+    fn invalidate(&self, _scope: Option<&str>) {
+        self.cached.clear();
+    }
+}
+
+impl DockerConfig {
+    fn read(path: &Path) -> Result<Self, BoxError> {
+        let contents = std::fs::read(path)
+            .map_err(|e| format!("{} cannot be read: {}", path.display(), e))?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| format!("{} is not a docker config: {}", path.display(), e).into())
+    }
+
+    /// Finds the credentials for `host` in the order docker does: a registry specific helper,
+    /// then the credentials stored in the config, then the default credential store.
+    fn source(&self, host: &str) -> Option<Source> {
+        if let Some((server, helper)) = self.cred_helpers.iter().find(|(key, _)| config_host(key) == host) {
+            return Some(Source::Helper(helper.clone(), server.clone()));
+        }
+
+        let stored = self.auths.iter().find(|(key, _)| config_host(key) == host);
+        if let Some(auth) = stored.and_then(|(_, entry)| entry.auth.clone()).filter(|auth| !auth.is_empty()) {
+            return Some(Source::Auth(auth));
+        }
+
+        let server = match stored {
+            Some((server, _)) => server.clone(),
+            None if host == config_host(DOCKER_HUB) => DOCKER_HUB.to_string(),
+            None => host.to_string(),
+        };
+        self.creds_store.clone().map(|store| Source::Helper(store, server))
+    }
+}
+
+/// Asks `docker-credential-<helper>` for the credentials of `server`.
+async fn run_helper(helper: &str, server: &str) -> Result<HelperCredentials, BoxError> {
+    let program = format!("docker-credential-{}", helper);
+    debug!("running {} for {}", program, server);
+    let mut child = Command::new(&program)
+        .arg("get")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // A helper that hangs is killed once the token refresh waiting for it times out.
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("{} cannot be run: {}", program, e))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(server.as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(if output.stdout.is_empty() { &output.stderr } else { &output.stdout });
+        return Err(format!("{} failed for {}: {}", program, server, message.trim()).into());
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| format!("{} returned invalid credentials: {}", program, e).into())
+}
+
+/// The host of a registry as keyed in docker configs, where Docker Hub has several names.
+fn registry_host(endpoint: &Url) -> String {
+    let host = endpoint.host_str().unwrap_or_default();
+    match (host, endpoint.port()) {
+        ("docker.io" | "index.docker.io" | "registry-1.docker.io", _) => config_host(DOCKER_HUB).to_string(),
+        (host, Some(port)) => format!("{}:{}", host, port),
+        (host, None) => host.to_string(),
+    }
+}
+
+/// Reduces a key of `auths` or `credHelpers`, which may be a URL, to its host.
+fn config_host(key: &str) -> &str {
+    let key = key.strip_prefix("https://").or_else(|| key.strip_prefix("http://")).unwrap_or(key);
+    key.split('/').next().unwrap_or(key)
+}
@@ -0,0 +1,342 @@
+use std::env;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aws_lc_rs::{digest, hmac};
+use bytes::Bytes;
+use http::{Method, Request};
+use http::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use http_body_util::{BodyExt, Full};
+use serde::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use crate::{upstream_client, BoxError, UpstreamClient};
+use crate::cache::to_hex;
+use crate::credentials::{secret_var, BoxFuture, CredentialProvider, TokenCache};
+
+const TARGET: &str = "AmazonEC2ContainerRegistry_V20150921.GetAuthorizationToken";
+
+const CONTENT: &str = "application/x-amz-json-1.1";
+
+/// The service name of the ECR API in request signatures.
+const SERVICE: &str = "ecr";
+
+/// Authorization tokens are valid for 12 hours and refreshed this long before they expire.
+const REFRESH_MARGIN: Duration = Duration::from_secs(30 * 60);
+
+/// Credentials for Amazon ECR, obtained from `GetAuthorizationToken` with AWS credentials.
+pub struct EcrCredentials {
+    endpoint: Url,
+    region: String,
+    client: UpstreamClient<Full<Bytes>>,
+    token: TokenCache,
+}
+
+impl std::fmt::Debug for EcrCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EcrCredentials")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .finish_non_exhaustive()
+    }
+}
+
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetAuthorizationTokenResponse {
+    authorization_data: Vec<AuthorizationData>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizationData {
+    authorization_token: String,
+    expires_at: f64,
+}
+
+impl EcrCredentials {
+    /// Prepares authentication for the ECR registry at `registry`, calling the ECR API at
+    /// `endpoint` if given, and at the regional endpoint otherwise.
+    ///
+    /// The region is taken from the registry host, falling back to `AWS_REGION`.
+    pub fn new(registry: &Url, endpoint: Option<&str>) -> Result<Self, BoxError> {
+        let region = registry_region(registry)
+            .or_else(|| env::var("AWS_REGION").ok())
+            .or_else(|| env::var("AWS_DEFAULT_REGION").ok())
+            .ok_or("The AWS region cannot be determined from the registry host, and AWS_REGION is not set")?;
+        let endpoint = match endpoint {
+            Some(endpoint) => Url::parse(endpoint)?,
+            None => Url::parse(&format!("https://api.ecr.{}.{}/", region, dns_suffix(registry, &region)))?,
+        };
+        AwsCredentials::load()?;
+
+        let client = upstream_client();
+
+        Ok(Self { endpoint, region, client, token: TokenCache::new(REFRESH_MARGIN) })
+    }
+
+    async fn get_authorization_token(&self) -> Result<AuthorizationData, BoxError> {
+        // Credentials are read again for every token, picking up rotated keys.
+        let credentials = AwsCredentials::load()?;
+        let body = Bytes::from_static(b"{}");
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{}:{}", self.endpoint.host_str().unwrap_or_default(), port),
+            None => self.endpoint.host_str().unwrap_or_default().to_string(),
+        };
+        let timestamp = amz_date(SystemTime::now());
+
+        let mut headers = vec![
+            ("content-type", CONTENT.to_string()),
+            ("host", host),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", TARGET.to_string()));
+        let authorization = sign(&credentials, &self.region, SERVICE, &self.endpoint, &timestamp, &headers, &body);
+
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.as_str())
+            .header(AUTHORIZATION, authorization);
+        for (name, value) in &headers {
+            // The client sets the host from the URI itself.
+            if *name != HOST.as_str() && *name != CONTENT_TYPE.as_str() {
+                req = req.header(*name, value);
+            }
+        }
+        let req = req.header(CONTENT_TYPE, CONTENT).body(Full::new(body))?;
+
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        let body = resp.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(format!("{} responded with {}: {}", self.endpoint, status, String::from_utf8_lossy(&body)).into());
+        }
+        let response: GetAuthorizationTokenResponse = serde_json::from_slice(&body)?;
+        response.authorization_data.into_iter().next()
+            .ok_or_else(|| "GetAuthorizationToken returned no authorization data".into())
+    }
+}
+
+impl CredentialProvider for EcrCredentials {
+    /// Returns the `Authorization` header for the registry, requesting a new authorization
+    /// token when the current one is about to expire.
+    fn authorization<'a>(&'a self, _scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(self.token.get(|| async {
+            let data = self.get_authorization_token().await?;
+            let expires_at = UNIX_EPOCH + Duration::from_secs_f64(data.expires_at.max(0.0));
+            debug!("obtained an ECR authorization token, expiring at {:?}", expires_at);
+            Ok((format!("Basic {}", data.authorization_token), expires_at))
+        }))
+    }
+
+    fn invalidate(&self, _scope: Option<&str>) {
+        self.token.clear();
+    }
+}
+
+impl AwsCredentials {
+    /// Reads credentials from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`, or the files named
+    /// by their `_FILE` variants, or from the profile `AWS_PROFILE` of the shared credentials file.
+    fn load() -> Result<Self, BoxError> {
+        if let (Some(access_key_id), Some(secret_access_key)) = (secret_var("AWS_ACCESS_KEY_ID")?, secret_var("AWS_SECRET_ACCESS_KEY")?) {
+            return Ok(Self { access_key_id, secret_access_key, session_token: secret_var("AWS_SESSION_TOKEN")? });
+        }
+
+        let path = match env::var("AWS_SHARED_CREDENTIALS_FILE") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => PathBuf::from(env::var("HOME").unwrap_or_default()).join(".aws/credentials"),
+        };
+        let profile = env::var("AWS_PROFILE").unwrap_or_else(|_| "default".to_string());
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("AWS credentials are not set in the environment, and {} cannot be read: {}", path.display(), e))?;
+
+        let mut section = String::new();
+        let (mut access_key_id, mut secret_access_key, mut session_token) = (None, None, None);
+        for line in contents.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = name.trim().to_string();
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=').filter(|_| section == profile) else {
+                continue;
+            };
+            let value = Some(value.trim().to_string());
+            match key.trim() {
+                "aws_access_key_id" => access_key_id = value,
+                "aws_secret_access_key" => secret_access_key = value,
+                "aws_session_token" => session_token = value,
+                _ => {}
+            }
+        }
+
+        match (access_key_id, secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Self { access_key_id, secret_access_key, session_token }),
+            _ => Err(format!("The profile '{}' in {} has no AWS credentials", profile, path.display()).into()),
+        }
+    }
+}
+
+/// The region in the host of an ECR registry, such as `<account>.dkr.ecr.<region>.amazonaws.com`.
+fn registry_region(registry: &Url) -> Option<String> {
+    registry.host_str()
+        .and_then(|host| host.split_once(".dkr.ecr."))
+        .and_then(|(_, rest)| rest.split_once('.'))
+        .map(|(region, _)| region.to_string())
+}
+
+/// The domain of the AWS partition serving an ECR registry, such as `amazonaws.com.cn` for the
+/// China regions. It is taken from the registry host, or from `region` for other hosts.
+fn dns_suffix(registry: &Url, region: &str) -> String {
+    let suffix = registry.host_str()
+        .and_then(|host| host.split_once(".dkr.ecr."))
+        .and_then(|(_, rest)| rest.split_once('.'))
+        .map(|(_, suffix)| suffix.to_string());
+    match suffix {
+        Some(suffix) => suffix,
+        None if region.starts_with("cn-") => "amazonaws.com.cn".to_string(),
+        None => "amazonaws.com".to_string(),
+    }
+}
+
+/// Computes the AWS Signature Version 4 `Authorization` header of a `POST` to `endpoint`, an API
+/// of `service` in `region`. `headers` must be lower-cased and sorted by name.
+fn sign(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    endpoint: &Url,
+    timestamp: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> String {
+    let date = &timestamp[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+    let canonical_headers: String = headers.iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let canonical_request = format!(
+        "POST\n{}\n{}\n{}\n{}\n{}",
+        endpoint.path(),
+        endpoint.query().unwrap_or_default(),
+        canonical_headers,
+        signed_headers,
+        to_hex(digest::digest(&digest::SHA256, body).as_ref()),
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        timestamp,
+        scope,
+        to_hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref()),
+    );
+
+    let secret = format!("AWS4{}", credentials.secret_access_key);
+    let mut key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()), date.as_bytes());
+    for part in [region, service, "aws4_request"] {
+        key = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key.as_ref()), part.as_bytes());
+    }
+    let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key.as_ref()), string_to_sign.as_bytes());
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        to_hex(signature.as_ref()),
+    )
+}
+
+/// Formats a time as the `YYYYMMDD'T'HHMMSS'Z'` timestamp used by Signature Version 4.
+fn amz_date(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // Converts days since the epoch to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, secs / 3_600, secs % 3_600 / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> String {
+        amz_date(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn amz_date_formats_civil_dates() {
+        assert_eq!(at(0), "19700101T000000Z");
+        assert_eq!(at(946_684_799), "19991231T235959Z");
+        assert_eq!(at(946_684_800), "20000101T000000Z");
+        assert_eq!(at(951_782_400), "20000229T000000Z");
+        assert_eq!(at(951_868_800), "20000301T000000Z");
+        // 2100 is not a leap year.
+        assert_eq!(at(4_107_456_000), "21000228T000000Z");
+        assert_eq!(at(4_107_542_400), "21000301T000000Z");
+    }
+
+    #[test]
+    fn sign_matches_aws_test_suite() {
+        // The `post-vanilla` case of the AWS Signature Version 4 test suite.
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: None,
+        };
+        let endpoint = Url::parse("https://example.amazonaws.com/").unwrap();
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        let authorization = sign(&credentials, "us-east-1", "service", &endpoint, "20150830T123600Z", &headers, b"");
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b",
+        );
+    }
+
+    #[test]
+    fn registry_region_is_parsed_from_host() {
+        let region = |host: &str| registry_region(&Url::parse(host).unwrap());
+        assert_eq!(region("https://123456789012.dkr.ecr.eu-west-1.amazonaws.com").as_deref(), Some("eu-west-1"));
+        assert_eq!(region("https://123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn").as_deref(), Some("cn-north-1"));
+        assert_eq!(region("https://registry.example.com"), None);
+        assert_eq!(region("https://123456789012.dkr.ecr"), None);
+    }
+
+    #[test]
+    fn dns_suffix_is_parsed_from_host() {
+        let suffix = |host: &str| dns_suffix(&Url::parse(host).unwrap(), "us-east-1");
+        assert_eq!(suffix("https://123456789012.dkr.ecr.eu-west-1.amazonaws.com"), "amazonaws.com");
+        assert_eq!(suffix("https://123456789012.dkr.ecr.cn-north-1.amazonaws.com.cn"), "amazonaws.com.cn");
+    }
+
+    #[test]
+    fn dns_suffix_falls_back_to_region() {
+        let registry = Url::parse("https://registry.example.com").unwrap();
+        assert_eq!(dns_suffix(&registry, "us-east-1"), "amazonaws.com");
+        assert_eq!(dns_suffix(&registry, "cn-northwest-1"), "amazonaws.com.cn");
+    }
+}
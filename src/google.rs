@@ -0,0 +1,162 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{RsaKeyPair, RSA_PKCS1_SHA256};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use http::{Method, Request};
+use http::header::CONTENT_TYPE;
+use http_body_util::{BodyExt, Full};
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::pki_types::pem::PemObject;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use url::{form_urlencoded, Url};
+
+use crate::{upstream_client, BoxError, UpstreamClient};
+use crate::credentials::{BoxFuture, CredentialProvider, TokenCache};
+
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+const SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Lifetime of the signed assertion, the longest Google accepts.
+const ASSERTION_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Access tokens are refreshed this long before they expire.
+const REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// A Google service account, exchanging JWTs signed with its key for OAuth2 access tokens.
+pub struct GoogleServiceAccount {
+    email: String,
+    key_id: Option<String>,
+    key: RsaKeyPair,
+    token_uri: Url,
+    client: UpstreamClient<Full<Bytes>>,
+    token: TokenCache,
+}
+
+impl std::fmt::Debug for GoogleServiceAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GoogleServiceAccount")
+            .field("email", &self.email)
+            .field("token_uri", &self.token_uri)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    private_key_id: Option<String>,
+    token_uri: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AssertionHeader<'a> {
+    alg: &'a str,
+    typ: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct AssertionClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+impl GoogleServiceAccount {
+    /// Reads a service account key file, as downloaded from the Google Cloud console.
+    pub fn load(path: &Path) -> Result<Self, BoxError> {
+        let contents = std::fs::read(path)?;
+        let key: ServiceAccountKey = serde_json::from_slice(&contents)
+            .map_err(|e| format!("{} is not a service account key: {}", path.display(), e))?;
+        let der = PrivatePkcs8KeyDer::from_pem_slice(key.private_key.as_bytes())
+            .map_err(|e| format!("The private key in {} cannot be read: {}", path.display(), e))?;
+        let pair = RsaKeyPair::from_pkcs8(der.secret_pkcs8_der())
+            .map_err(|e| format!("The private key in {} is not a valid RSA key: {}", path.display(), e))?;
+        let token_uri = Url::parse(key.token_uri.as_deref().unwrap_or(DEFAULT_TOKEN_URI))?;
+
+        let client = upstream_client();
+
+        Ok(Self {
+            email: key.client_email,
+            key_id: key.private_key_id,
+            key: pair,
+            token_uri,
+            client,
+            token: TokenCache::new(REFRESH_MARGIN),
+        })
+    }
+
+    async fn exchange(&self) -> Result<TokenResponse, BoxError> {
+        let body = form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer")
+            .append_pair("assertion", &self.assertion()?)
+            .finish();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.token_uri.as_str())
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Full::new(Bytes::from(body)))?;
+
+        let resp = self.client.request(req).await?;
+        let status = resp.status();
+        let body = resp.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(format!("{} responded with {}: {}", self.token_uri, status, String::from_utf8_lossy(&body)).into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Signs the RS256 JWT asserting the service account's identity to the token endpoint.
+    fn assertion(&self) -> Result<String, BoxError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let header = AssertionHeader { alg: "RS256", typ: "JWT", kid: self.key_id.as_deref() };
+        let claims = AssertionClaims {
+            iss: &self.email,
+            scope: SCOPE,
+            aud: self.token_uri.as_str(),
+            iat: now.as_secs(),
+            exp: (now + ASSERTION_LIFETIME).as_secs(),
+        };
+
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?);
+        let signing_input = format!("{}.{}", header, payload);
+        let mut signature = vec![0; self.key.public_modulus_len()];
+        self.key.sign(&RSA_PKCS1_SHA256, &SystemRandom::new(), signing_input.as_bytes(), &mut signature)
+            .map_err(|_| "Unable to sign the service account assertion")?;
+        Ok(format!("{}.{}", signing_input, URL_SAFE_NO_PAD.encode(signature)))
+    }
+}
+
+impl CredentialProvider for GoogleServiceAccount {
+    /// Returns the `Authorization` header for registries, exchanging a new access token when
+    /// the current one is about to expire.
+    fn authorization<'a>(&'a self, _scope: Option<&'a str>) -> BoxFuture<'a, Result<String, BoxError>> {
+        Box::pin(self.token.get(|| async {
+            let response = self.exchange().await?;
+            debug!("obtained an access token for {}, valid for {}s", self.email, response.expires_in);
+            let header = format!("Basic {}", STANDARD.encode(format!("oauth2accesstoken:{}", response.access_token)));
+            Ok((header, SystemTime::now() + Duration::from_secs(response.expires_in)))
+        }))
+    }
+
+    fn invalidate(&self, _scope: Option<&str>) {
+        self.token.clear();
+    }
+}
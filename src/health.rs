@@ -0,0 +1,38 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+/// How long an upstream may take to answer the readiness probe.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a readiness result is reused before upstreams are probed again.
+pub const PROBE_CACHE: Duration = Duration::from_secs(10);
+
+/// The most recent readiness probe result, shared by all connections and kept across reloads
+/// that leave the upstreams unchanged.
+#[derive(Debug, Default)]
+pub struct Readiness {
+    last: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl Readiness {
+    /// Returns the cached result if it is recent enough, and otherwise runs `probe`.
+    ///
+    /// Concurrent callers wait for a single probe instead of each contacting the upstreams.
+    pub async fn check<F>(&self, probe: F) -> Result<(), String>
+    where
+        F: Future<Output = Result<(), String>>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((checked, result)) = last.as_ref() {
+            if checked.elapsed() < PROBE_CACHE {
+                return result.clone();
+            }
+        }
+        
+        let result = probe.await;
+        *last = Some((Instant::now(), result.clone()));
+        result
+    }
+}
@@ -1,29 +1,105 @@
-use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD;
-use tracing::{error, info};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use tracing::info;
 use url::Url;
 
+mod acr;
+mod auth;
+mod broker;
+mod cache;
+mod config;
+mod credentials;
+mod docker;
+mod ecr;
+mod google;
+mod health;
+pub mod metrics;
 mod proxy;
+mod ratelimit;
+mod reload;
+pub mod tls;
+mod token;
+pub use acr::AcrCredentials;
+pub use auth::{Htpasswd, Identity};
+pub use broker::TokenBroker;
+use auth::matches;
+pub use cache::{BlobCache, ManifestCache};
+pub use credentials::{BoxFuture, CredentialProvider, FileCredentials, StaticCredentials, TokenCache};
+use credentials::read_secret;
+pub use docker::DockerCredentials;
+pub use ecr::EcrCredentials;
+pub use google::GoogleServiceAccount;
+pub use config::{
+    AuthConfig, BindConfig, BucketConfig, CacheConfig, Config, GrantConfig, RateLimitConfig, RegistryConfig, TlsConfig,
+};
+use config::route_key;
+pub use health::Readiness;
 pub use proxy::ProxyService;
+pub use ratelimit::{RateLimiter, RequestKind};
+pub use reload::spawn_reloader;
+pub use token::TokenIssuer;
+
+pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The client for requests to upstream registries and the token endpoints of credential providers.
+pub(crate) type UpstreamClient<B> = Client<HttpsConnector<HttpConnector>, B>;
+
+/// Builds a client for upstream requests, connecting over HTTPS with the webpki roots or over
+/// plain HTTP.
+pub(crate) fn upstream_client<B>() -> UpstreamClient<B>
+where
+    B: hyper::body::Body + Send,
+    B::Data: Send,
+{
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Client::builder(TokioExecutor::new()).build(https)
+}
 
 pub static PACKAGE_NAME: &str = env!("CARGO_PKG_NAME");
 
 #[derive(Debug, Clone)]
 pub struct AppState {
-    pub auth: Option<String>,
     pub hostname: Option<String>,
-    pub registry: Registry,
+    pub registries: Vec<Registry>,
+    pub cache: Option<BlobCache>,
+    pub manifest_cache: Option<ManifestCache>,
+    /// Users allowed to access conex. Clients are not authenticated if unset.
+    pub htpasswd: Option<Arc<Htpasswd>>,
+    /// Issues conex's own tokens, verified locally before proxying.
+    pub token_issuer: Option<Arc<TokenIssuer>>,
+    pub readiness: Arc<Readiness>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Largest request body, in bytes, forwarded upstream.
+    pub max_body_size: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Registry {
+    pub route: Option<String>,
     pub endpoint: Url,
     pub token_endpoint: Url,
+    /// The `service` the token endpoint expects, if its challenge names one.
+    pub service: Option<String>,
     pub repo_prefix: String,
+    /// Credentials presented upstream, obtained through the token broker if it is enabled.
+    pub auth: Option<Arc<dyn CredentialProvider>>,
+    /// Repository patterns that may be pulled through this registry. Everything is allowed if empty.
+    pub allow: Vec<String>,
+    /// Repository patterns that may never be pulled through this registry.
+    pub deny: Vec<String>,
+    /// The configuration the registry was built from, and the modification time of the
+    /// service account key it loaded, to tell whether a reload can keep it.
+    source: (RegistryConfig, Option<SystemTime>),
 }
 
 #[derive(Debug, Clone)]
@@ -32,116 +108,273 @@ pub struct Bind {
     pub port: Option<u16>,
 }
 
-impl Default for Bind {
-    fn default() -> Self {
+impl Bind {
+    pub fn new(config: &Config) -> Self {
         Self {
-            host: env::var("BIND_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()).into(),
-            port: env::var("BIND_PORT").unwrap_or_else(|_| "8080".to_string()).parse().ok(),
+            host: config.bind.host.clone().or_else(|| Some("0.0.0.0".to_string())),
+            port: config.bind.port.or(Some(8080)),
         }
     }
 }
 
 impl AppState {
-    pub async fn new() -> Self {
-        let mut auth: Option<String> = None;
-        if let Ok(key) = env::var("GOOGLE_APPLICATION_CREDENTIALS") {
-            let mut file = match File::open(key) {
-                Ok(file) => file,
-                Err(_) => {
-                    error!("GOOGLE_APPLICATION_CREDENTIALS is set, but the file cannot be opened.");
-                    std::process::exit(1);
-                }
-            };
-            let mut contents = String::new();
-            file.read_to_string(&mut contents).as_ref().unwrap();
-            let base64 = STANDARD.encode(format!("_json_key:{}", contents).as_bytes());
-            info!("Google service account authentication is configured.");
-            auth = Some(format!("Basic {}", base64));
-        } else if let Ok(basic) = env::var("AUTH_HEADER") {
-            info!("Authentication header is configured.");
-            auth = Some(basic);
+    pub async fn new(config: &Config) -> Result<Self, BoxError> {
+        Self::build(config, None).await
+    }
+
+    /// Builds the state for a reloaded `config`, keeping what it leaves unchanged: the rate
+    /// limiter buckets, the readiness result, and the registries along with their discovered
+    /// token endpoints and credential providers, whose tokens stay cached.
+    pub async fn reload(&self, config: &Config) -> Result<Self, BoxError> {
+        Self::build(config, Some(self)).await
+    }
+
+    async fn build(config: &Config, previous: Option<&Self>) -> Result<Self, BoxError> {
+        let limits = &config.rate_limit;
+        if let Some(bucket) = &limits.manifests {
+            bucket.validate("rate_limit.manifests")?;
         }
-        
-        let registry = Registry::new().await;
-        
-        Self {
-            auth,
-            hostname: env::var("HOSTNAME").ok(),
-            registry,
+        if let Some(bucket) = &limits.blobs {
+            bucket.validate("rate_limit.blobs")?;
+        }
+
+        let previous_registry = |route: Option<&str>| {
+            previous?.registries.iter().find(|registry| registry.route.as_deref() == route)
+        };
+        let mut registries = Vec::new();
+        if let Some(registry) = &config.registry {
+            registries.push(Registry::new(None, registry, previous_registry(None)).await?);
         }
+        for (route, registry) in &config.routes {
+            if route.is_empty() || route.contains('/') {
+                return Err(format!("Invalid route name: {}", route).into());
+            }
+            registries.push(Registry::new(Some(route), registry, previous_registry(Some(route))).await?);
+        }
+        if registries.is_empty() {
+            return Err("No registry is configured".into());
+        }
+
+        let mut cache = None;
+        let mut manifest_cache = None;
+        if let Some(dir) = &config.cache.dir {
+            let ttl = Duration::from_secs(config.cache.manifest_ttl.unwrap_or(0));
+            cache = Some(BlobCache::new(dir)
+                .map_err(|e| format!("{} cannot be used as a cache directory: {}", dir.display(), e))?);
+            manifest_cache = Some(ManifestCache::new(dir, ttl)
+                .map_err(|e| format!("{} cannot be used as a cache directory: {}", dir.display(), e))?);
+            info!("Cache is enabled at {}", dir.display());
+        }
+
+        let htpasswd = match &config.auth.htpasswd {
+            Some(path) => {
+                let htpasswd = Htpasswd::load(path)?;
+                info!("Client authentication is enabled with {}", path.display());
+                Some(Arc::new(htpasswd))
+            }
+            None => None,
+        };
+
+        let token_secret = match &config.auth.token_secret_file {
+            Some(path) => Some(read_secret(path)
+                .map_err(|e| format!("AUTH_TOKEN_SECRET_FILE is set, but {}", e))?),
+            None => config.auth.token_secret.clone(),
+        };
+        let token_issuer = token_secret.map(|secret| {
+            let ttl = Duration::from_secs(config.auth.token_ttl.unwrap_or(300));
+            info!("Token server is enabled");
+            Arc::new(TokenIssuer::new(&secret, ttl, config.auth.grants.clone()))
+        });
+
+        let kept_limiter = previous
+            .and_then(|previous| previous.rate_limiter.clone())
+            .filter(|limiter| limiter.limits(&limits.manifests, &limits.blobs));
+        let rate_limiter = match kept_limiter {
+            Some(limiter) => Some(limiter),
+            None => (limits.manifests.is_some() || limits.blobs.is_some()).then(|| {
+                info!("Rate limiting is enabled");
+                Arc::new(RateLimiter::new(limits.manifests.clone(), limits.blobs.clone()))
+            }),
+        };
+
+        // The readiness result only holds for the same upstreams.
+        let readiness = previous
+            .filter(|previous| previous.registries.iter().map(|r| &r.endpoint).eq(registries.iter().map(|r| &r.endpoint)))
+            .map(|previous| previous.readiness.clone())
+            .unwrap_or_default();
+
+        Ok(Self {
+            hostname: config.hostname.clone(),
+            registries,
+            cache,
+            manifest_cache,
+            htpasswd,
+            token_issuer,
+            readiness,
+            rate_limiter,
+            max_body_size: config.max_body_size,
+        })
+    }
+
+    /// Selects the registry serving `name`, a path below `/v2/`.
+    ///
+    /// Returns the registry along with the remainder of `name` once the route segment is removed.
+    pub fn route<'a>(&self, name: &'a str) -> Option<(&Registry, &'a str)> {
+        if let Some((route, rest)) = name.split_once('/') {
+            if let Some(registry) = self.registries.iter().find(|r| r.route.as_deref() == Some(route)) {
+                return Some((registry, rest));
+            }
+        }
+
+        self.registries.iter()
+            .find(|r| r.route.is_none())
+            .map(|registry| (registry, name))
+    }
+
+    /// The registry used for requests that do not name a repository, such as the `/v2/` ping.
+    pub fn default_registry(&self) -> &Registry {
+        &self.registries[0]
     }
 }
 
 impl Registry {
-    async fn new() -> Self {
-        let endpoint = match Url::parse(&env::var("REGISTRY_HOST").unwrap_or_else(|_| "https://index.docker.io".to_string())) {
-            Ok(url) => url,
-            Err(e) => {
-                error!("REGISTRY_HOST is not a valid URL: {}", e);
-                std::process::exit(1);
-            }
+    /// Builds the registry for `config`, reusing `previous`, the registry the same route had
+    /// before a reload, where the configuration allows.
+    async fn new(route: Option<&str>, config: &RegistryConfig, previous: Option<&Registry>) -> Result<Self, BoxError> {
+        let key_modified = config.google_application_credentials.as_deref().and_then(modified_time);
+        let source = (config.clone(), key_modified);
+        if let Some(previous) = previous.filter(|previous| previous.source == source) {
+            return Ok(previous.clone());
+        }
+
+        let host = config.host.as_deref().unwrap_or("https://index.docker.io");
+        let endpoint = Url::parse(host)
+            .map_err(|e| format!("{} is not a valid URL: {}", route_key(route, "REGISTRY_HOST"), e))?;
+        let (token_endpoint, service) = match previous.filter(|previous| previous.endpoint == endpoint) {
+            Some(previous) => (previous.token_endpoint.clone(), previous.service.clone()),
+            None => discover_token(endpoint.clone()).await?,
         };
-        let token_endpoint = discover_token(endpoint.clone()).await;
-        Self {
+        let repo_prefix = match &config.prefix {
+            Some(prefix) => prefix.trim_matches('/').to_string(),
+            None => return Err(format!("{} is not set", route_key(route, "REGISTRY_PREFIX")).into()),
+        };
+        let mut auth = load_auth(route, &endpoint, config)?;
+        if config.token_broker {
+            info!("Token broker is enabled for {}", endpoint);
+            auth = Some(Arc::new(TokenBroker::new(token_endpoint.clone(), service.clone(), auth)));
+        }
+        
+        Ok(Self {
+            route: route.map(String::from),
             endpoint,
             token_endpoint,
-            repo_prefix: match env::var("REGISTRY_PREFIX") {
-                Ok(prefix) => prefix,
-                Err(_) => {
-                    error!("REGISTRY_PREFIX is not set");
-                    std::process::exit(1);
-                }
-            },
+            service,
+            repo_prefix,
+            auth,
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            source,
+        })
+    }
+
+    /// Whether the repository `name`, relative to the prefix, passes the allow and deny lists.
+    pub fn permits(&self, name: &str) -> bool {
+        if self.deny.iter().any(|pattern| matches(pattern, name)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|pattern| matches(pattern, name))
+    }
+
+    /// Maps a repository name as seen by clients to its name on the upstream registry.
+    pub fn upstream_name(&self, name: &str) -> String {
+        if self.repo_prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", self.repo_prefix, name)
+        }
+    }
+
+    /// Maps a path below `/v2/` on the upstream registry back to the path seen by clients.
+    /// Returns `None` if the path is outside of the repository prefix.
+    pub fn client_name(&self, upstream_name: &str) -> Option<String> {
+        let name = if self.repo_prefix.is_empty() {
+            upstream_name
+        } else {
+            upstream_name.strip_prefix(self.repo_prefix.as_str())?.strip_prefix('/')?
+        };
+        match &self.route {
+            Some(route) => Some(format!("{}/{}", route, name)),
+            None => Some(name.to_string()),
         }
     }
 }
 
-async fn discover_token(registry_host: Url) -> Url {
+fn load_auth(route: Option<&str>, endpoint: &Url, config: &RegistryConfig) -> Result<Option<Arc<dyn CredentialProvider>>, BoxError> {
+    if let Some(key) = &config.google_application_credentials {
+        let account = GoogleServiceAccount::load(key)
+            .map_err(|e| format!("{} is set, but the key cannot be loaded: {}", route_key(route, "GOOGLE_APPLICATION_CREDENTIALS"), e))?;
+        info!("Google service account authentication is configured.");
+        Ok(Some(Arc::new(account)))
+    } else if config.aws_ecr {
+        let credentials = EcrCredentials::new(endpoint, config.aws_ecr_endpoint.as_deref())
+            .map_err(|e| format!("{} is set, but ECR authentication cannot be configured: {}", route_key(route, "AWS_ECR"), e))?;
+        info!("Amazon ECR authentication is configured.");
+        Ok(Some(Arc::new(credentials)))
+    } else if config.azure_acr {
+        let credentials = AcrCredentials::new(endpoint)
+            .map_err(|e| format!("{} is set, but ACR authentication cannot be configured: {}", route_key(route, "AZURE_ACR"), e))?;
+        info!("Azure Container Registry authentication is configured.");
+        Ok(Some(Arc::new(credentials)))
+    } else if let Some(path) = &config.docker_config {
+        let credentials = DockerCredentials::load(path, endpoint)
+            .map_err(|e| format!("{} is set, but the credentials cannot be loaded: {}", route_key(route, "DOCKER_CONFIG"), e))?;
+        info!("Docker config authentication is configured.");
+        Ok(Some(Arc::new(credentials)))
+    } else if let Some(path) = &config.auth_header_file {
+        let credentials = FileCredentials::load(path)
+            .map_err(|e| format!("{} is set, but {}", route_key(route, "AUTH_HEADER_FILE"), e))?;
+        info!("Authentication header file is configured.");
+        Ok(Some(Arc::new(credentials)))
+    } else if let Some(basic) = &config.auth_header {
+        info!("Authentication header is configured.");
+        Ok(Some(Arc::new(StaticCredentials::new(basic.clone()))))
+    } else {
+        Ok(None)
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Locates the token endpoint of a registry, along with the `service` it expects, from the
+/// challenge of its `/v2/` endpoint.
+async fn discover_token(registry_host: Url) -> Result<(Url, Option<String>), BoxError> {
     use hyper::{Request, Uri};
-    use hyper_util::client::legacy::Client;
-    use hyper_util::rt::TokioExecutor;
     
     let url = format!("{}v2/", registry_host);
-    let uri = Uri::try_from(url.clone()).unwrap();
-    
-    let https = hyper_rustls::HttpsConnectorBuilder::new()
-        .with_webpki_roots()
-        .https_only()
-        .enable_http1()
-        .build();
+    let uri = Uri::try_from(url.clone())?;
     
-    let client: Client<_, http_body_util::Empty<bytes::Bytes>> = 
-        Client::builder(TokioExecutor::new()).build(https);
+    let client: UpstreamClient<http_body_util::Empty<bytes::Bytes>> = upstream_client();
     
     let req = Request::builder()
         .uri(uri)
-        .body(http_body_util::Empty::new())
-        .unwrap();
+        .body(http_body_util::Empty::new())?;
     
-    let res = match client.request(req).await {
-        Ok(res) => res,
-        Err(e) => {
-            error!("Unable to discover the token endpoint of the target registry: {}", e);
-            std::process::exit(1);
-        }
-    };
+    let res = client.request(req).await
+        .map_err(|e| format!("Unable to discover the token endpoint of the target registry: {}", e))?;
     
-    let hdr = match res.headers().get("www-authenticate") {
-        Some(hdr) => hdr,
-        None => {
-            error!("'www-authenticate' header is not present, unable to locate the token endpoint");
-            std::process::exit(1);
-        }
-    };
+    let hdr = res.headers().get("www-authenticate")
+        .ok_or("'www-authenticate' header is not present, unable to locate the token endpoint")?;
 
-    let realm = match hdr.to_str().unwrap().split(',').find(|s| s.contains("realm")) {
-        Some(s) => s.split('=').last().unwrap().replace("\"", ""),
-        None => {
-            error!("'www-authenticate' header does not contain 'realm' attribute, unable to locate the token endpoint");
-            std::process::exit(1);
-        }
+    let realm = match hdr.to_str()?.split(',').find(|s| s.contains("realm")) {
+        Some(s) => s.split('=').next_back().unwrap_or_default().replace("\"", ""),
+        None => return Err("'www-authenticate' header does not contain 'realm' attribute, unable to locate the token endpoint".into()),
     };
+    let service = hdr.to_str()?.split(',')
+        .find_map(|s| s.trim().strip_prefix("service="))
+        .map(|s| s.replace("\"", ""));
     info!("Discovered token endpoint: {}", realm);
 
-    Url::parse(&realm).unwrap()
-}
\ No newline at end of file
+    Ok((Url::parse(&realm)?, service))
+}
@@ -1,18 +1,25 @@
 use std::sync::Arc;
 use std::io;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use hyper::server::conn::{http1, http2};
 use hyper_util::rt::{TokioIo, TokioExecutor};
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
 use tokio::net::TcpStream;
 use tokio::net::TcpListener;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
 use tracing::{info, debug, Level};
 
-use conex::{AppState, Bind, ProxyService};
+use conex::metrics::METRICS;
+use conex::{spawn_reloader, tls, AppState, Bind, Config, ProxyService};
 
 const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+/// How long a client has to complete the TLS handshake before the connection is dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 struct BufferedStream {
     stream: TcpStream,
@@ -105,53 +112,96 @@ async fn detect_http2_preface(mut stream: TcpStream) -> Result<(bool, BufferedSt
     }
 }
 
+/// Returns the path given by `--config <path>` or `--config=<path>`.
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::fmt()
         .with_max_level(Level::INFO)
         .init();
 
-    let state = Arc::new(AppState::new().await);
+    let config_path = config_path();
+    let config = match Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let state = match AppState::new(&config).await {
+        Ok(state) => Arc::new(state),
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let (states, _) = watch::channel(state);
+    spawn_reloader(config_path, states.clone());
 
-    let bind = Bind::default();
+    let bind = Bind::new(&config);
     let addr = format!("{}:{}", bind.host.unwrap(), bind.port.unwrap());
     
+    let acceptor = match tls::acceptor(&config.tls) {
+        Ok(acceptor) => acceptor,
+        Err(e) => {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    
     let listener = TcpListener::bind(&addr).await?;
-    info!("listening on {}", addr);
+    info!("listening on {}{}", addr, if acceptor.is_some() { " (TLS)" } else { "" });
+
+    let graceful = GracefulShutdown::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let state = state.clone();
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = &mut shutdown => break,
+        };
+        let states = states.subscribe();
+        let acceptor = acceptor.clone();
+        let watcher = graceful.watcher();
 
         tokio::task::spawn(async move {
-            let service = ProxyService::new(state);
+            let service = ProxyService::new(states).with_remote_addr(remote_addr);
+            
+            if let Some(acceptor) = acceptor {
+                // Negotiate HTTP version with ALPN
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(tls_stream)) => {
+                        let is_http2 = tls_stream.get_ref().1.alpn_protocol() == Some(b"h2");
+                        serve_connection(tls_stream, is_http2, service.with_tls(true), watcher).await;
+                    }
+                    Ok(Err(err)) => {
+                        debug!("Error accepting TLS connection: {:?}", err);
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {} timed out", remote_addr);
+                    }
+                }
+                return;
+            }
             
             // Detect HTTP version
             match detect_http2_preface(stream).await {
                 Ok((is_http2, detected_stream)) => {
-                    let io = TokioIo::new(detected_stream);
-                    
-                    if is_http2 {
-                        // Serve HTTP/2 connection
-                        info!("Serving HTTP/2 connection");
-                        if let Err(err) = http2::Builder::new(TokioExecutor::new())
-                            .serve_connection(io, service)
-                            .await
-                        {
-                            tracing::error!("Error serving HTTP/2 connection: {:?}", err);
-                        }
-                    } else {
-                        // Serve HTTP/1 connection
-                        debug!("Serving HTTP/1.1 connection");
-                        if let Err(err) = http1::Builder::new()
-                            .preserve_header_case(true)
-                            .title_case_headers(true)
-                            .serve_connection(io, service)
-                            .await
-                        {
-                            tracing::error!("Error serving HTTP/1 connection: {:?}", err);
-                        }
-                    }
+                    serve_connection(detected_stream, is_http2, service, watcher).await;
                 }
                 Err(err) => {
                     tracing::error!("Error detecting HTTP version: {:?}", err);
@@ -159,4 +209,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             }
         });
     }
+
+    // Stop accepting and let in-flight requests finish
+    drop(listener);
+    let drain_timeout = Duration::from_secs(config.drain_timeout.unwrap_or(30));
+    info!("Shutting down, draining connections for up to {:?}", drain_timeout);
+    tokio::select! {
+        _ = graceful.shutdown() => info!("All connections are closed"),
+        _ = tokio::time::sleep(drain_timeout) => tracing::warn!("Drain timeout elapsed, closing remaining connections"),
+    }
+    Ok(())
+}
+
+/// Resolves on `SIGTERM` or `SIGINT`.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        
+        let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn serve_connection<I>(stream: I, is_http2: bool, service: ProxyService, watcher: Watcher)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+    let _connection = METRICS.connection(if is_http2 { "http2" } else { "http1" });
+    
+    if is_http2 {
+        // Serve HTTP/2 connection
+        info!("Serving HTTP/2 connection");
+        let conn = http2::Builder::new(TokioExecutor::new())
+            .serve_connection(io, service);
+        if let Err(err) = watcher.watch(conn).await {
+            tracing::error!("Error serving HTTP/2 connection: {:?}", err);
+        }
+    } else {
+        // Serve HTTP/1 connection
+        debug!("Serving HTTP/1.1 connection");
+        let conn = http1::Builder::new()
+            .preserve_header_case(true)
+            .title_case_headers(true)
+            .serve_connection(io, service);
+        if let Err(err) = watcher.watch(conn).await {
+            tracing::error!("Error serving HTTP/1 connection: {:?}", err);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,132 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, SizeHint};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+type BoxBody = http_body_util::combinators::BoxBody<Bytes, io::Error>;
+
+/// Process-wide metrics, kept across configuration reloads.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Requests served, by route and response status.
+    pub requests: IntCounterVec,
+    /// Time until response headers are sent, by route.
+    pub request_duration: HistogramVec,
+    /// Responses received from upstream registries, by status.
+    pub upstream_responses: IntCounterVec,
+    /// Requests to upstream registries that failed without a response.
+    pub upstream_errors: IntCounter,
+    /// Body bytes transferred, by route and direction.
+    pub bytes: IntCounterVec,
+    /// Open client connections, by protocol.
+    pub connections: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+        
+        let requests = IntCounterVec::new(
+            Opts::new("conex_requests_total", "Requests served, by route and response status"),
+            &["route", "status"],
+        ).unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("conex_request_duration_seconds", "Time until response headers are sent, by route"),
+            &["route"],
+        ).unwrap();
+        let upstream_responses = IntCounterVec::new(
+            Opts::new("conex_upstream_responses_total", "Responses received from upstream registries, by status"),
+            &["status"],
+        ).unwrap();
+        let upstream_errors = IntCounter::new(
+            "conex_upstream_errors_total",
+            "Requests to upstream registries that failed without a response",
+        ).unwrap();
+        let bytes = IntCounterVec::new(
+            Opts::new("conex_bytes_total", "Body bytes transferred, by route and direction"),
+            &["route", "direction"],
+        ).unwrap();
+        let connections = IntGaugeVec::new(
+            Opts::new("conex_active_connections", "Open client connections, by protocol"),
+            &["protocol"],
+        ).unwrap();
+        
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry.register(Box::new(request_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_responses.clone())).unwrap();
+        registry.register(Box::new(upstream_errors.clone())).unwrap();
+        registry.register(Box::new(bytes.clone())).unwrap();
+        registry.register(Box::new(connections.clone())).unwrap();
+        
+        Self { registry, requests, request_duration, upstream_responses, upstream_errors, bytes, connections }
+    }
+
+    /// Renders all metrics in the Prometheus text format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let _ = TextEncoder::new().encode(&self.registry.gather(), &mut buffer);
+        buffer
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub fn connection(&self, protocol: &str) -> ConnectionGuard {
+        let gauge = self.connections.with_label_values(&[protocol]);
+        gauge.inc();
+        ConnectionGuard(gauge)
+    }
+}
+
+pub struct ConnectionGuard(IntGauge);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// A body that counts the bytes of its data frames as they are polled.
+pub(crate) struct CountingBody {
+    inner: BoxBody,
+    counter: IntCounter,
+}
+
+impl CountingBody {
+    pub(crate) fn new(inner: BoxBody, counter: IntCounter) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl Body for CountingBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.counter.inc_by(data.len() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -1,160 +1,945 @@
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Instant;
 
 use bytes::Bytes;
 use http::{Method, Request, Response, StatusCode, Uri};
-use http::header::{HeaderValue, HOST, AUTHORIZATION};
-use http_body_util::{BodyExt, Full};
+use http::header::{HeaderValue, HOST, ACCEPT, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LINK, LOCATION, RETRY_AFTER, WWW_AUTHENTICATE};
+use http_body_util::{BodyExt, Empty, Full, LengthLimitError, Limited};
 use hyper::body::Incoming;
 use hyper::service::Service;
-use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
-use tracing::info;
-use url::Url;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use url::{form_urlencoded, Url};
 
-use crate::{AppState, PACKAGE_NAME};
+use crate::auth::valid_name;
+use crate::cache::CachedManifest;
+use crate::health;
+use crate::metrics::{CountingBody, METRICS};
+use crate::token::{self, Claims, TokenIssuer};
+use crate::{upstream_client, AppState, BoxError, Identity, Registry, RequestKind, UpstreamClient, PACKAGE_NAME};
 
 type BoxBody = http_body_util::combinators::BoxBody<Bytes, std::io::Error>;
-type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Repositories listed per catalog page when the client does not ask for a number.
+const CATALOG_PAGE_SIZE: usize = 100;
+
+const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+
+#[derive(Deserialize)]
+struct Index {
+    #[serde(default)]
+    manifests: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Catalog {
+    #[serde(default)]
+    repositories: Vec<String>,
+}
 
 #[derive(Clone)]
 pub struct ProxyService {
+    states: watch::Receiver<Arc<AppState>>,
+    /// The state snapshot serving the current request.
     state: Arc<AppState>,
-    client: Client<hyper_rustls::HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>, BoxBody>,
+    /// Whether the connection was accepted over TLS by conex itself.
+    tls: bool,
+    /// The address of the connected client.
+    remote_addr: Option<IpAddr>,
+    client: UpstreamClient<BoxBody>,
 }
 
 impl ProxyService {
-    pub fn new(state: Arc<AppState>) -> Self {
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_webpki_roots()
-            .https_or_http()
-            .enable_http1()
-            .build();
-        
-        let client = Client::builder(TokioExecutor::new()).build(https);
+    pub fn new(states: watch::Receiver<Arc<AppState>>) -> Self {
+        let client = upstream_client();
         
-        Self { state, client }
+        let state = states.borrow().clone();
+        Self { states, state, tls: false, remote_addr: None, client }
     }
 
-    fn rewrite_registry_v2_url(&self, uri: &Uri) -> Url {
+    pub fn with_tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn with_remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.remote_addr = Some(addr.ip());
+        self
+    }
+
+    fn rewrite_registry_v2_url(&self, uri: &Uri) -> Option<(&Registry, Url)> {
         let path = uri.path();
-        let registry = &self.state.registry;
         
-        let new_path = if path == "/v2/" {
-            path.to_string()
+        let (registry, new_path) = if path == "/v2/" {
+            (self.state.default_registry(), path.to_string())
         } else {
-            path.replace("/v2/", &format!("/v2/{}/", registry.repo_prefix))
+            let (registry, name) = self.state.route(path.strip_prefix("/v2/")?)?;
+            (registry, format!("/v2/{}", registry.upstream_name(name)))
         };
 
         let mut url = registry.endpoint.clone();
         url.set_path(&new_path);
+        if url.path() != new_path {
+            // The path had segments resolved or characters escaped, and would reach another
+            // repository than the one it was checked for.
+            warn!("refused to rewrite {} into {}", path, url);
+            return None;
+        }
         
         if let Some(query) = uri.query() {
             url.set_query(Some(query));
         }
         
         info!("rewrote url: {} into {}", uri, url);
-        url
+        Some((registry, url))
     }
 
-    fn rewrite_token_scope(&self, query: &str) -> String {
-        let parts: Vec<&str> = query.split('&').collect();
-        let mut new_parts = Vec::new();
+    /// Rewrites the `scope` parameters of a token request into upstream repository names.
+    ///
+    /// The registry is selected by the first repository scope; scopes routed to any other
+    /// registry are dropped since a single token endpoint cannot grant them.
+    fn rewrite_token_scope(&self, query: &str) -> (&Registry, String) {
+        let mut registry: Option<&Registry> = None;
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
         
-        for part in parts {
-            if part.starts_with("scope=") {
-                let new_scope = part.replace("repository:", &format!("repository:{}/", self.state.registry.repo_prefix));
-                new_parts.push(new_scope);
-            } else {
-                new_parts.push(part.to_string());
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            if key != "scope" {
+                serializer.append_pair(&key, &value);
+                continue;
+            }
+            
+            let mut scopes = Vec::new();
+            for scope in value.split(' ').filter(|s| !s.is_empty()) {
+                let Some((name, actions)) = scope.strip_prefix("repository:").and_then(|s| s.rsplit_once(':')) else {
+                    scopes.push(scope.to_string());
+                    continue;
+                };
+                if !valid_name(name) {
+                    info!("dropped scope for invalid repository name: {}", scope);
+                    continue;
+                }
+                let Some((target, name)) = self.state.route(name) else {
+                    continue;
+                };
+                if !target.permits(name) {
+                    info!("dropped scope for denied repository: {}", scope);
+                    continue;
+                }
+                if registry.is_some_and(|r| !std::ptr::eq(r, target)) {
+                    continue;
+                }
+                registry = Some(target);
+                scopes.push(format!("repository:{}:{}", target.upstream_name(name), actions));
+            }
+            
+            if !scopes.is_empty() {
+                serializer.append_pair("scope", &scopes.join(" "));
             }
         }
         
-        new_parts.join("&")
+        (registry.unwrap_or_else(|| self.state.default_registry()), serializer.finish())
+    }
+
+    /// Sends a request to an upstream registry, recording its outcome in the metrics.
+    async fn upstream(&self, req: Request<BoxBody>) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+        let result = self.client.request(req).await;
+        match &result {
+            Ok(resp) => METRICS.upstream_responses.with_label_values(&[resp.status().as_str()]).inc(),
+            Err(_) => METRICS.upstream_errors.inc(),
+        }
+        result
     }
 
-    async fn proxy_request(&self, req: Request<Incoming>) -> Result<Response<BoxBody>, BoxError> {
+    /// Whether the declared length of a request body exceeds `max_body_size`. Bodies without a
+    /// declared length are cut off by [`Self::request_body`] once they reach the limit instead.
+    fn body_too_large(&self, req: &Request<Incoming>) -> bool {
+        let Some(limit) = self.state.max_body_size else {
+            return false;
+        };
+        req.headers().get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .is_some_and(|len| len > limit)
+    }
+
+    /// Streams a client request body to the upstream as it arrives, so that uploads are never
+    /// held in memory and a slow upstream slows down the client in turn.
+    fn request_body(&self, body: Incoming, route: &str) -> BoxBody {
+        let body = match self.state.max_body_size {
+            Some(limit) => Limited::new(body, usize::try_from(limit).unwrap_or(usize::MAX))
+                .map_err(std::io::Error::other)
+                .boxed(),
+            None => body.map_err(std::io::Error::other).boxed(),
+        };
+        CountingBody::new(body, METRICS.bytes.with_label_values(&[route, "received"])).boxed()
+    }
+
+    /// Checks that every upstream registry answers its `/v2/` endpoint in time. Any response
+    /// counts, since an unauthenticated ping is expected to be rejected.
+    async fn probe_upstreams(&self) -> Result<(), String> {
+        for registry in &self.state.registries {
+            let url = format!("{}v2/", registry.endpoint);
+            let body = Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed();
+            let req = Request::builder()
+                .uri(url.as_str())
+                .body(body)
+                .map_err(|e| e.to_string())?;
+            
+            match tokio::time::timeout(health::PROBE_TIMEOUT, self.client.request(req)).await {
+                Ok(Ok(resp)) if !resp.status().is_server_error() => {}
+                Ok(Ok(resp)) => return Err(format!("{} responded with {}", url, resp.status())),
+                Ok(Err(e)) => return Err(format!("{} is unreachable: {}", url, e)),
+                Err(_) => return Err(format!("{} did not respond within {:?}", url, health::PROBE_TIMEOUT)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn proxy_request(&self, mut req: Request<Incoming>) -> Result<Response<BoxBody>, BoxError> {
         let uri = req.uri().clone();
         let path = uri.path();
+        let token_path = format!("/{}/token", PACKAGE_NAME);
+        // With the token server enabled, the API is authorized with conex's tokens instead.
+        let basic_auth = path == token_path || (path.starts_with("/v2/") && self.state.token_issuer.is_none());
+        
+        if basic_auth {
+            if let Some(htpasswd) = &self.state.htpasswd {
+                let Some(identity) = htpasswd.authenticate(req.headers()).await else {
+                    return unauthorized_response();
+                };
+                // The client's credentials are for conex only and must not reach the upstream.
+                req.headers_mut().remove(AUTHORIZATION);
+                req.extensions_mut().insert(identity);
+            }
+        }
         
         if path.starts_with("/v2/") {
+            if !valid_api_path(path) {
+                return error_response(StatusCode::BAD_REQUEST, "NAME_INVALID", "invalid repository name");
+            }
+            if let Some(issuer) = &self.state.token_issuer {
+                if let Some(rejection) = self.authorize_bearer(issuer, &mut req)? {
+                    return Ok(rejection);
+                }
+                if path == "/v2/" {
+                    return api_version_response();
+                }
+            }
+            if let Some(rejection) = self.rate_limit(&req)? {
+                return Ok(rejection);
+            }
             self.handle_registry_api(req).await
-        } else if path == format!("/{}/token", PACKAGE_NAME) {
-            self.handle_token_proxy(req).await
+        } else if path == token_path {
+            match &self.state.token_issuer {
+                Some(issuer) => self.handle_token_issue(issuer, req),
+                None => self.handle_token_proxy(req).await,
+            }
+        } else if path == "/metrics" {
+            handle_metrics()
+        } else if path == "/healthz" {
+            health_response(Ok(()))
+        } else if path == "/readyz" {
+            let result = self.state.readiness.check(self.probe_upstreams()).await;
+            health_response(result)
         } else {
             self.handle_redirect(&uri)
         }
     }
 
+    /// The scheme and host under which clients reach conex.
+    fn local_origin(&self, uri: &Uri, host: Option<&HeaderValue>) -> String {
+        let hostname = self.state.hostname.clone()
+            .or_else(|| host.and_then(|h| h.to_str().ok().map(String::from)))
+            .or_else(|| uri.authority().map(|a| a.to_string()))
+            .unwrap_or_else(|| "localhost".to_string());
+            
+        let scheme = if self.tls || !hostname.starts_with("localhost") { "https" } else { "http" };
+        format!("{}://{}", scheme, hostname)
+    }
+
+    /// The token endpoint of conex advertised to clients.
+    fn local_token_realm(&self, uri: &Uri, host: Option<&HeaderValue>) -> String {
+        format!("{}/{}/token", self.local_origin(uri, host), PACKAGE_NAME)
+    }
+
+    /// Maps a `Location` returned by the upstream back to conex and the client's repository name.
+    ///
+    /// Relative locations stay relative. Locations on other hosts, such as blob storage, are
+    /// left alone.
+    fn local_location(&self, registry: &Registry, location: &str, origin: &str) -> Option<String> {
+        let url = registry.endpoint.join(location).ok()?;
+        if url.origin() != registry.endpoint.origin() {
+            return None;
+        }
+        let name = registry.client_name(url.path().strip_prefix("/v2/")?)?;
+        
+        let origin = if location.starts_with('/') { "" } else { origin };
+        let mut local = format!("{}/v2/{}", origin, name);
+        if let Some(query) = url.query() {
+            local.push('?');
+            local.push_str(query);
+        }
+        Some(local)
+    }
+
+    /// Maps every target of a `Link` header back to conex, such as the next page of a tag list.
+    fn rewrite_link(&self, registry: &Registry, link: &str, origin: &str) -> String {
+        let mut rewritten = String::new();
+        let mut rest = link;
+        while let Some((before, after)) = rest.split_once('<') {
+            let Some((target, after)) = after.split_once('>') else {
+                break;
+            };
+            rewritten.push_str(before);
+            rewritten.push('<');
+            match self.local_location(registry, target, origin) {
+                Some(target) => rewritten.push_str(&target),
+                None => rewritten.push_str(target),
+            }
+            rewritten.push('>');
+            rest = after;
+        }
+        rewritten.push_str(rest);
+        rewritten
+    }
+
+    /// Rewrites a bearer challenge of the upstream to send clients to the token endpoint of
+    /// conex, with scopes naming repositories as clients see them. Other schemes are kept.
+    fn rewrite_challenge(&self, registry: &Registry, challenge: &str, realm: &str) -> Option<String> {
+        let (scheme, params) = challenge.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Bearer") {
+            return None;
+        }
+        
+        let params = challenge_params(params).into_iter().map(|(key, value)| {
+            let value = match key.as_str() {
+                "realm" => realm.to_string(),
+                "service" if self.state.token_issuer.is_some() => token::SERVICE.to_string(),
+                "scope" => value.split(' ')
+                    .filter_map(|scope| client_scope(registry, scope))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                _ => value,
+            };
+            format!("{}=\"{}\"", key, value)
+        });
+        Some(format!("Bearer {}", params.collect::<Vec<_>>().join(",")))
+    }
+
+    /// Rewrites the source repository of a cross-repository blob mount into its upstream name.
+    ///
+    /// A mount from a repository on another registry, or one the client may not pull, cannot
+    /// be honored. Its parameters are dropped so that the upstream starts a regular upload.
+    fn rewrite_mount(&self, registry: &Registry, query: &str, claims: Option<&Claims>) -> String {
+        let from = form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "from")
+            .map(|(_, value)| value.into_owned());
+        let source = from.as_deref()
+            .filter(|from| valid_name(from))
+            .and_then(|from| self.state.route(from))
+            .filter(|(source, _)| source.route == registry.route)
+            .map(|(_, name)| name)
+            .filter(|name| registry.permits(name))
+            .filter(|name| claims.is_none_or(|claims| claims.allows(name, "pull")));
+        
+        let mut serializer = form_urlencoded::Serializer::new(String::new());
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            match (&*key, source) {
+                ("from", Some(name)) => serializer.append_pair("from", &registry.upstream_name(name)),
+                ("from" | "mount", None) => continue,
+                _ => serializer.append_pair(&key, &value),
+            };
+        }
+        serializer.finish()
+    }
+
+    /// Verifies the bearer token issued by conex against the repository and action of `req`,
+    /// replacing the token with the identity it was issued to. Returns the challenge to respond
+    /// with if the request is not authorized.
+    fn authorize_bearer(&self, issuer: &TokenIssuer, req: &mut Request<Incoming>) -> Result<Option<Response<BoxBody>>, BoxError> {
+        let path = req.uri().path().strip_prefix("/v2/");
+        let catalog = path.and_then(|path| self.state.route(path)).is_some_and(|(_, rest)| rest == "_catalog");
+        let name = path.and_then(repository_name);
+        if name.is_some_and(|name| !valid_name(name)) {
+            return error_response(StatusCode::BAD_REQUEST, "NAME_INVALID", "invalid repository name").map(Some);
+        }
+        let scope = if catalog {
+            Some(token::CATALOG_SCOPE.to_string())
+        } else {
+            name.map(|name| format!("repository:{}:{}", name, action(req.method())))
+        };
+        let realm = self.local_token_realm(req.uri(), req.headers().get(HOST));
+        
+        let claims = req.headers().get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .and_then(|token| issuer.verify(token.trim()));
+        let Some(claims) = claims else {
+            return bearer_challenge(&realm, scope.as_deref(), None).map(Some);
+        };
+        let allowed = if catalog {
+            claims.allows_catalog()
+        } else {
+            name.is_none_or(|name| claims.allows(name, action(req.method())))
+        };
+        if !allowed {
+            return bearer_challenge(&realm, scope.as_deref(), Some("insufficient_scope")).map(Some);
+        }
+        
+        req.headers_mut().remove(AUTHORIZATION);
+        req.extensions_mut().insert(Identity(claims.sub.clone()));
+        req.extensions_mut().insert(claims);
+        Ok(None)
+    }
+
+    /// Applies the rate limit of the requesting client, keyed by its identity if authenticated
+    /// and by its address otherwise. Returns the response to reject the request with.
+    fn rate_limit(&self, req: &Request<Incoming>) -> Result<Option<Response<BoxBody>>, BoxError> {
+        let Some(limiter) = &self.state.rate_limiter else {
+            return Ok(None);
+        };
+        let path = req.uri().path();
+        let kind = if manifest_reference(path).is_some() {
+            RequestKind::Manifest
+        } else if path.contains("/blobs/") {
+            RequestKind::Blob
+        } else {
+            return Ok(None);
+        };
+        
+        let client = match (req.extensions().get::<Identity>(), self.remote_addr) {
+            (Some(identity), _) if !identity.0.is_empty() => format!("user:{}", identity.0),
+            (_, Some(addr)) => addr.to_string(),
+            _ => return Ok(None),
+        };
+        let Err(wait) = limiter.check(kind, &client) else {
+            return Ok(None);
+        };
+        
+        info!("rate limited {} on {:?} requests", client, kind);
+        let mut response = error_response(StatusCode::TOO_MANY_REQUESTS, "TOOMANYREQUESTS", "too many requests")?;
+        response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(wait.as_secs_f64().ceil() as u64));
+        Ok(Some(response))
+    }
+
+    fn handle_token_issue(&self, issuer: &TokenIssuer, req: Request<Incoming>) -> Result<Response<BoxBody>, BoxError> {
+        let query = req.uri().query().unwrap_or("");
+        let scopes: Vec<String> = form_urlencoded::parse(query.as_bytes())
+            .filter(|(key, _)| key == "scope")
+            .map(|(_, value)| value.into_owned())
+            .collect();
+        
+        let identity = req.extensions().get::<Identity>();
+        let token = issuer.issue(identity, &scopes);
+        info!("issued token to '{}' for {:?}", identity.map(|i| i.0.as_str()).unwrap_or_default(), scopes);
+        
+        let body = Full::new(Bytes::from(serde_json::to_vec(&token)?)).map_err(|e: std::convert::Infallible| match e {}).boxed();
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .map_err(|e| {
+                tracing::error!("Failed to build token response: {}", e);
+                Box::new(e) as BoxError
+            })
+    }
+
     async fn handle_registry_api(&self, req: Request<Incoming>) -> Result<Response<BoxBody>, BoxError> {
         let uri = req.uri().clone();
         let method = req.method().clone();
-        let url = self.rewrite_registry_v2_url(&uri);
+        let route = uri.path().strip_prefix("/v2/").and_then(|path| self.state.route(path));
+        if let Some((registry, "_catalog")) = route.filter(|_| method == Method::GET) {
+            return self.handle_catalog(registry, &req).await;
+        }
+        let Some((registry, mut url)) = self.rewrite_registry_v2_url(&uri) else {
+            return error_response(StatusCode::NOT_FOUND, "NAME_UNKNOWN", "repository name not known to registry");
+        };
+        let upload = uri.path().ends_with("/blobs/uploads/") && method == Method::POST;
+        if let Some(query) = uri.query().filter(|_| upload) {
+            let query = self.rewrite_mount(registry, query, req.extensions().get::<Claims>());
+            url.set_query((!query.is_empty()).then_some(query.as_str()));
+        }
+        
+        let name = uri.path().strip_prefix("/v2/")
+            .and_then(|path| self.state.route(path))
+            .and_then(|(_, path)| repository_name(path));
+        if name.is_some_and(|name| !registry.permits(name)) {
+            return error_response(StatusCode::FORBIDDEN, "DENIED", "requested access to the resource is denied");
+        }
+        if self.body_too_large(&req) {
+            return error_response(StatusCode::PAYLOAD_TOO_LARGE, "SIZE_INVALID", "request body exceeds the maximum size");
+        }
+        if let Some(digest) = referrers_digest(uri.path()).filter(|_| method == Method::GET) {
+            return self.handle_referrers(registry, &req, url, digest).await;
+        }
+        
+        let blob = blob_digest(uri.path()).filter(|_| method == Method::GET || method == Method::HEAD);
+        if let (Some(cache), Some(digest), Some(name)) = (&self.state.cache, blob, name) {
+            if let Some((body, len)) = cache.open(registry, digest).await {
+                // The blob may have been cached from a repository the client cannot access, so
+                // the upstream is asked the first time it is requested through a repository.
+                let seen = cache.seen_in(registry, name, digest).await;
+                if !seen || !self.serves_cache_directly(registry) {
+                    if let Some(rejection) = self.authorize_cached(registry, &req, &url).await? {
+                        return Ok(rejection);
+                    }
+                }
+                if !seen {
+                    cache.record(registry, name, digest).await;
+                }
+                return cached_blob_response(&method, digest, body, len);
+            }
+        }
         
         let mut headers = req.headers().clone();
         let original_host_header = headers.get(HOST).cloned();
         
+        let manifest = manifest_reference(uri.path()).filter(|_| method == Method::GET || method == Method::HEAD);
+        let accept = headers.get_all(ACCEPT).iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let (Some(cache), Some(reference)) = (&self.state.manifest_cache, manifest) {
+            if let Some(cached) = cache.get(&url, &accept, reference).await {
+                if !self.serves_cache_directly(registry) {
+                    if let Some(rejection) = self.authorize_cached(registry, &req, &url).await? {
+                        return Ok(rejection);
+                    }
+                }
+                return cached_manifest_response(&method, cached);
+            }
+        }
+        
         if let Ok(host_value) = HeaderValue::from_str(url.host_str().unwrap_or("")) {
             headers.insert(HOST, host_value);
         }
         
-        if let Some(auth) = &self.state.auth {
-            if let Ok(auth_value) = HeaderValue::from_str(auth) {
+        let auth = self.upstream_auth(registry, &method, &url).await;
+        if let Some(auth_value) = auth.as_deref().and_then(|auth| HeaderValue::from_str(auth).ok()) {
+            headers.insert(AUTHORIZATION, auth_value);
+        }
+        
+        let body = self.request_body(req.into_body(), "api");
+        
+        let new_uri = Uri::try_from(url.as_str()).map_err(|e| Box::new(e) as BoxError)?;
+        let client_req = upstream_request(&method, &new_uri, &headers, body)?;
+        
+        let mut client_resp = match self.upstream(client_req).await {
+            Ok(resp) => resp,
+            Err(e) if exceeds_body_limit(&e) => {
+                return error_response(StatusCode::PAYLOAD_TOO_LARGE, "SIZE_INVALID", "request body exceeds the maximum size");
+            }
+            Err(e) => {
+                tracing::error!("Failed to execute request: {}", e);
+                return Err(Box::new(e));
+            }
+        };
+        
+        // Credentials may have been revoked or expired early. Only requests without a body are
+        // retried, as the body has been consumed by the first attempt.
+        let retry = method == Method::GET || method == Method::HEAD;
+        if let Some(auth) = auth.filter(|_| retry && client_resp.status() == StatusCode::UNAUTHORIZED) {
+            let renewed = self.renew_upstream_auth(registry, &method, &url, &auth).await;
+            if let Some(auth_value) = renewed.as_deref().and_then(|auth| HeaderValue::from_str(auth).ok()) {
                 headers.insert(AUTHORIZATION, auth_value);
+                let body = Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed();
+                client_resp = self.upstream(upstream_request(&method, &new_uri, &headers, body)?).await
+                    .map_err(|e| {
+                        tracing::error!("Failed to execute request: {}", e);
+                        Box::new(e) as BoxError
+                    })?;
             }
         }
         
-        let body = req.into_body().collect().await.map_err(|e| Box::new(e) as BoxError)?.to_bytes();
-        let body = Full::new(body).map_err(|e: std::convert::Infallible| match e {}).boxed();
+        // Blobs are usually served through a redirect to storage, which the client would
+        // otherwise follow on its own and bypass the cache.
+        let cacheable = self.state.cache.is_some() && blob.is_some() && method == Method::GET;
+        if cacheable && client_resp.status().is_redirection() {
+            client_resp = self.follow_redirect(&url, client_resp).await?;
+        }
         
-        let new_uri = Uri::try_from(url.as_str()).map_err(|e| Box::new(e) as BoxError)?;
-        let mut client_req = Request::builder()
-            .method(method)
-            .uri(new_uri);
+        let status = client_resp.status();
+        let mut response = self.response_builder(registry, &client_resp, &uri, original_host_header.as_ref());
         
-        for (key, value) in headers.iter() {
-            client_req = client_req.header(key, value);
+        if let (Some(cache), Some(digest), Some(name)) = (&self.state.cache, blob, name) {
+            if status.is_success() {
+                cache.record(registry, name, digest).await;
+            }
         }
         
-        let client_req = client_req.body(body).map_err(|e| Box::new(e) as BoxError)?;
+        // A pushed or deleted manifest replaces whatever was cached under the same reference.
+        if let Some(cache) = &self.state.manifest_cache {
+            let modifies = method == Method::PUT || method == Method::DELETE;
+            if modifies && status.is_success() && manifest_reference(uri.path()).is_some() {
+                cache.evict(&url).await;
+            }
+        }
         
-        let client_resp = self.client.request(client_req).await
+        if uri.path() == "/v2/" && !client_resp.headers().contains_key(WWW_AUTHENTICATE) {
+            let realm = self.local_token_realm(&uri, original_host_header.as_ref());
+            response = response.header(WWW_AUTHENTICATE, format!("Bearer realm=\"{}\"", realm));
+        }
+        
+        if method == Method::GET && status == StatusCode::OK && uri.path().ends_with("/tags/list") {
+            let name = uri.path().strip_prefix("/v2/").and_then(|p| p.strip_suffix("/tags/list")).unwrap_or_default();
+            let body = client_resp.into_body().collect().await.map_err(|e| Box::new(e) as BoxError)?.to_bytes();
+            let body = rewrite_tag_list(body, name);
+            
+            if let Some(headers) = response.headers_mut() {
+                headers.remove(CONTENT_LENGTH);
+            }
+            let body = Full::new(body).map_err(|e: std::convert::Infallible| match e {}).boxed();
+            return response.body(body).map_err(|e| {
+                tracing::error!("Failed to build response: {}", e);
+                Box::new(e) as BoxError
+            });
+        }
+        
+        if let (Some(cache), Some(reference)) = (&self.state.manifest_cache, manifest) {
+            if method == Method::GET && status == StatusCode::OK {
+                let header = |name| client_resp.headers().get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(String::from);
+                let content_type = header(CONTENT_TYPE.as_str());
+                let digest = header("Docker-Content-Digest");
+                let body = client_resp.into_body().collect().await.map_err(|e| Box::new(e) as BoxError)?.to_bytes();
+                
+                let cached = CachedManifest { content_type, digest, body };
+                cache.put(&url, &accept, reference, &cached).await;
+                
+                let body = Full::new(cached.body).map_err(|e: std::convert::Infallible| match e {}).boxed();
+                return response.body(body).map_err(|e| {
+                    tracing::error!("Failed to build response: {}", e);
+                    Box::new(e) as BoxError
+                });
+            }
+        }
+        
+        let mut body = client_resp.into_body().map_err(std::io::Error::other).boxed();
+        if let (Some(cache), Some(digest)) = (&self.state.cache, blob) {
+            if cacheable && status == StatusCode::OK {
+                body = cache.tee(registry, digest, body);
+            }
+        }
+        response.body(body).map_err(|e| {
+            tracing::error!("Failed to build response: {}", e);
+            Box::new(e) as BoxError
+        })
+    }
+
+    /// Whether content cached from `registry` may be served without asking the upstream, which
+    /// is the case when conex holds the upstream credentials itself or has authenticated the
+    /// client. Otherwise clients bring their own upstream credentials, which only the upstream
+    /// can check.
+    fn serves_cache_directly(&self, registry: &Registry) -> bool {
+        registry.auth.is_some() || self.state.htpasswd.is_some() || self.state.token_issuer.is_some()
+    }
+
+    /// Asks the upstream with a `HEAD` request whether the client may access `url`, with the
+    /// credentials configured for the registry or otherwise those the client sent. Returns the
+    /// upstream response to reject the request with if not.
+    async fn authorize_cached(&self, registry: &Registry, req: &Request<Incoming>, url: &Url) -> Result<Option<Response<BoxBody>>, BoxError> {
+        let mut headers = req.headers().clone();
+        if let Ok(host_value) = HeaderValue::from_str(url.host_str().unwrap_or("")) {
+            headers.insert(HOST, host_value);
+        }
+        let auth = self.upstream_auth(registry, &Method::HEAD, url).await;
+        if let Some(auth_value) = auth.as_deref().and_then(|auth| HeaderValue::from_str(auth).ok()) {
+            headers.insert(AUTHORIZATION, auth_value);
+        }
+        let uri = Uri::try_from(url.as_str()).map_err(|e| Box::new(e) as BoxError)?;
+        let body = Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed();
+        let resp = self.upstream(upstream_request(&Method::HEAD, &uri, &headers, body)?).await
             .map_err(|e| {
                 tracing::error!("Failed to execute request: {}", e);
                 Box::new(e) as BoxError
             })?;
+        if resp.status().is_success() || resp.status().is_redirection() {
+            return Ok(None);
+        }
         
-        let status = client_resp.status();
-        let mut response = Response::builder().status(status);
+        debug!("upstream rejected access to cached content at {}: {}", url, resp.status());
+        let mut response = self.response_builder(registry, &resp, req.uri(), req.headers().get(HOST));
+        // The length is that of the body a `GET` would have had.
+        if let Some(headers) = response.headers_mut() {
+            headers.remove(CONTENT_LENGTH);
+        }
+        let body = Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed();
+        response.body(body).map(Some).map_err(|e| Box::new(e) as BoxError)
+    }
+
+    /// Starts the response to a client from an upstream response.
+    ///
+    /// Clients must never be sent to the upstream directly, nor see its repository names, so
+    /// headers referring to either are rewritten.
+    fn response_builder(&self, registry: &Registry, resp: &Response<Incoming>, uri: &Uri, host: Option<&HeaderValue>) -> http::response::Builder {
+        let origin = self.local_origin(uri, host);
+        let realm = self.local_token_realm(uri, host);
+        let mut response = Response::builder().status(resp.status());
         
-        for (key, value) in client_resp.headers() {
-            response = response.header(key.as_str(), value.as_bytes());
+        for (key, value) in resp.headers() {
+            let rewritten = value.to_str().ok().and_then(|value| {
+                if key == LOCATION {
+                    self.local_location(registry, value, &origin)
+                } else if key == LINK {
+                    Some(self.rewrite_link(registry, value, &origin))
+                } else if key == WWW_AUTHENTICATE {
+                    self.rewrite_challenge(registry, value, &realm)
+                } else {
+                    None
+                }
+            });
+            match rewritten {
+                Some(value) => response = response.header(key, value),
+                None => response = response.header(key, value),
+            }
         }
+        response
+    }
+
+    /// Lists the repositories below the repository prefix, named as clients see them.
+    ///
+    /// The upstream catalog covers every repository, so its pages are read from the start of the
+    /// prefix until enough repositories are found or the prefix is passed.
+    async fn handle_catalog(&self, registry: &Registry, req: &Request<Incoming>) -> Result<Response<BoxBody>, BoxError> {
+        let query = req.uri().query().unwrap_or("");
+        let param = |name: &str| form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned());
+        let limit = param("n").and_then(|n| n.parse::<usize>().ok()).unwrap_or(CATALOG_PAGE_SIZE);
+        let prefix = if registry.repo_prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", registry.repo_prefix)
+        };
+        let mut last = param("last")
+            .and_then(|last| {
+                let (route, name) = self.state.route(&last)?;
+                (route.route == registry.route).then(|| registry.upstream_name(name))
+            })
+            .unwrap_or_else(|| prefix.clone());
         
-        if uri.path() == "/v2/" {
-            let hostname = self.state.hostname.clone()
-                .or_else(|| original_host_header.and_then(|h| h.to_str().ok().map(String::from)))
-                .unwrap_or_else(|| "localhost".to_string());
-                
-            let scheme = if hostname.starts_with("localhost") { "http" } else { "https" };
-            let local_token = format!("{}://{}/{}/token", scheme, hostname, PACKAGE_NAME);
+        let mut repositories = Vec::new();
+        let mut more = true;
+        while more && repositories.len() < limit {
+            let mut url = registry.endpoint.clone();
+            url.set_path("/v2/_catalog");
+            url.query_pairs_mut().append_pair("n", &limit.to_string());
+            if !last.is_empty() {
+                url.query_pairs_mut().append_pair("last", &last);
+            }
+            
+            let resp = self.upstream_get(registry, &url, None).await?;
+            if resp.status() != StatusCode::OK {
+                let response = self.response_builder(registry, &resp, req.uri(), req.headers().get(HOST));
+                let body = resp.into_body().map_err(std::io::Error::other).boxed();
+                return response.body(body).map_err(|e| Box::new(e) as BoxError);
+            }
+            
+            more = resp.headers().get_all(LINK).iter().any(|link| link.to_str().is_ok_and(|l| l.contains("rel=\"next\"")));
+            let body = resp.into_body().collect().await.map_err(|e| Box::new(e) as BoxError)?.to_bytes();
+            let page: Catalog = serde_json::from_slice(&body)?;
+            match page.repositories.last() {
+                Some(repository) => last = repository.clone(),
+                None => more = false,
+            }
             
-            response = response.header("www-authenticate", format!("Bearer realm=\"{}\"", local_token));
+            for repository in &page.repositories {
+                let Some(name) = repository.strip_prefix(prefix.as_str()) else {
+                    more = false;
+                    break;
+                };
+                if repositories.len() == limit {
+                    more = true;
+                    break;
+                }
+                let client_name = registry.client_name(repository).filter(|_| registry.permits(name));
+                if let Some(client_name) = client_name.filter(|name| self.grants_pull(req, name)) {
+                    repositories.push(client_name);
+                }
+            }
         }
         
-        let body = client_resp.into_body().map_err(std::io::Error::other).boxed();
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json");
+        if let (true, Some(last)) = (more, repositories.last()) {
+            let path = req.uri().path();
+            let mut serializer = form_urlencoded::Serializer::new(String::new());
+            serializer.append_pair("n", &limit.to_string());
+            serializer.append_pair("last", last);
+            response = response.header(LINK, format!("<{}?{}>; rel=\"next\"", path, serializer.finish()));
+        }
+        
+        let body = serde_json::to_vec(&Catalog { repositories })?;
+        let body = Full::new(Bytes::from(body)).map_err(|e: std::convert::Infallible| match e {}).boxed();
         response.body(body).map_err(|e| {
-            tracing::error!("Failed to build response: {}", e);
+            tracing::error!("Failed to build catalog response: {}", e);
             Box::new(e) as BoxError
         })
     }
 
+    /// Whether the grants of the token server allow the client of `req` to pull `name`. Clients
+    /// are not restricted if conex does not issue tokens.
+    fn grants_pull(&self, req: &Request<Incoming>, name: &str) -> bool {
+        let (Some(issuer), Some(claims)) = (&self.state.token_issuer, req.extensions().get::<Claims>()) else {
+            return true;
+        };
+        let user = Some(claims.sub.as_str()).filter(|sub| !sub.is_empty());
+        issuer.permits(user, name, "pull")
+    }
+
+    /// Lists the manifests referring to `digest`.
+    ///
+    /// Registries without the referrers API are asked for the index tagged `<alg>-<hex>` instead,
+    /// which clients maintain for such registries. Results are filtered by `artifactType` if
+    /// the upstream did not.
+    async fn handle_referrers(&self, registry: &Registry, req: &Request<Incoming>, url: Url, digest: &str) -> Result<Response<BoxBody>, BoxError> {
+        let artifact_type = form_urlencoded::parse(req.uri().query().unwrap_or("").as_bytes())
+            .find(|(key, _)| key == "artifactType")
+            .map(|(_, value)| value.into_owned());
+        
+        let mut resp = self.upstream_get(registry, &url, Some(OCI_INDEX)).await?;
+        let mut filtered = resp.headers().get_all("OCI-Filters-Applied").iter()
+            .any(|v| v.to_str().is_ok_and(|v| v.split(',').any(|f| f.trim() == "artifactType")));
+        
+        if matches!(resp.status(), StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED) {
+            let Some((repository, _)) = url.path().rsplit_once("/referrers/") else {
+                return error_response(StatusCode::NOT_FOUND, "NAME_UNKNOWN", "repository name not known to registry");
+            };
+            let mut tag_url = url.clone();
+            tag_url.set_path(&format!("{}/manifests/{}", repository, digest.replacen(':', "-", 1)));
+            tag_url.set_query(None);
+            debug!("referrers API is not supported, falling back to {}", tag_url);
+            
+            resp = self.upstream_get(registry, &tag_url, Some(OCI_INDEX)).await?;
+            filtered = false;
+        }
+        
+        // Pages of a large referrers list are linked to like those of a tag list.
+        let link = resp.headers().get(LINK).and_then(|link| link.to_str().ok()).map(|link| {
+            self.rewrite_link(registry, link, &self.local_origin(req.uri(), req.headers().get(HOST)))
+        });
+        let mut manifests = match resp.status() {
+            StatusCode::OK => {
+                let body = resp.into_body().collect().await.map_err(|e| Box::new(e) as BoxError)?.to_bytes();
+                serde_json::from_slice::<Index>(&body).map(|index| index.manifests).unwrap_or_default()
+            }
+            // Nothing has been pushed to the fallback tag yet.
+            StatusCode::NOT_FOUND => Vec::new(),
+            _ => {
+                let response = self.response_builder(registry, &resp, req.uri(), req.headers().get(HOST));
+                let body = resp.into_body().map_err(std::io::Error::other).boxed();
+                return response.body(body).map_err(|e| Box::new(e) as BoxError);
+            }
+        };
+        
+        let mut response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, OCI_INDEX);
+        if let Some(artifact_type) = artifact_type {
+            if !filtered {
+                manifests.retain(|m| m.get("artifactType").and_then(|t| t.as_str()) == Some(artifact_type.as_str()));
+            }
+            response = response.header("OCI-Filters-Applied", "artifactType");
+        }
+        if let Some(link) = link {
+            response = response.header(LINK, link);
+        }
+        
+        let index = serde_json::json!({ "schemaVersion": 2, "mediaType": OCI_INDEX, "manifests": manifests });
+        let body = Full::new(Bytes::from(serde_json::to_vec(&index)?)).map_err(|e: std::convert::Infallible| match e {}).boxed();
+        response.body(body).map_err(|e| {
+            tracing::error!("Failed to build referrers response: {}", e);
+            Box::new(e) as BoxError
+        })
+    }
+
+    /// The `Authorization` to send with a request to `url`, from the credentials configured for
+    /// the registry.
+    async fn upstream_auth(&self, registry: &Registry, method: &Method, url: &Url) -> Option<String> {
+        let auth = registry.auth.as_ref()?;
+        auth.authorization(upstream_scope(method, url).as_deref()).await
+            .inspect_err(|e| warn!("Failed to obtain upstream credentials: {}", e))
+            .ok()
+    }
+
+    /// Discards the credentials the registry rejected for a request to `url`, returning new ones
+    /// to retry with if they differ from the rejected `auth`.
+    async fn renew_upstream_auth(&self, registry: &Registry, method: &Method, url: &Url, auth: &str) -> Option<String> {
+        let provider = registry.auth.as_ref()?;
+        provider.invalidate(upstream_scope(method, url).as_deref());
+        let renewed = self.upstream_auth(registry, method, url).await.filter(|renewed| renewed != auth)?;
+        debug!("upstream rejected the credentials for {}, retrying with renewed ones", url);
+        Some(renewed)
+    }
+
+    /// Sends a `GET` to the registry with the credentials configured for it, renewing them once
+    /// if they are rejected.
+    async fn upstream_get(&self, registry: &Registry, url: &Url, accept: Option<&str>) -> Result<Response<Incoming>, BoxError> {
+        let auth = self.upstream_auth(registry, &Method::GET, url).await;
+        let resp = self.send_get(url, auth.as_deref(), accept).await?;
+        let Some(auth) = auth.filter(|_| resp.status() == StatusCode::UNAUTHORIZED) else {
+            return Ok(resp);
+        };
+        match self.renew_upstream_auth(registry, &Method::GET, url, &auth).await {
+            Some(auth) => self.send_get(url, Some(&auth), accept).await,
+            None => Ok(resp),
+        }
+    }
+
+    async fn send_get(&self, url: &Url, auth: Option<&str>, accept: Option<&str>) -> Result<Response<Incoming>, BoxError> {
+        let mut req = Request::builder().uri(url.as_str());
+        if let Some(auth) = auth {
+            req = req.header(AUTHORIZATION, auth);
+        }
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept);
+        }
+        let body = Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed();
+        let req = req.body(body).map_err(|e| Box::new(e) as BoxError)?;
+        
+        self.upstream(req).await
+            .map_err(|e| {
+                tracing::error!("Failed to execute request: {}", e);
+                Box::new(e) as BoxError
+            })
+    }
+
+    async fn follow_redirect(&self, base: &Url, resp: Response<Incoming>) -> Result<Response<Incoming>, BoxError> {
+        let Some(location) = resp.headers().get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| base.join(l).ok())
+        else {
+            return Ok(resp);
+        };
+        debug!("following blob redirect to {}", location);
+        
+        // Credentials are meant for the registry only and are not forwarded to storage.
+        let body = Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed();
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri(Uri::try_from(location.as_str()).map_err(|e| Box::new(e) as BoxError)?)
+            .body(body)
+            .map_err(|e| Box::new(e) as BoxError)?;
+        
+        self.upstream(req).await
+            .map_err(|e| {
+                tracing::error!("Failed to follow blob redirect: {}", e);
+                Box::new(e) as BoxError
+            })
+    }
+
     async fn handle_token_proxy(&self, req: Request<Incoming>) -> Result<Response<BoxBody>, BoxError> {
+        if self.body_too_large(&req) {
+            return error_response(StatusCode::PAYLOAD_TOO_LARGE, "SIZE_INVALID", "request body exceeds the maximum size");
+        }
         let query = req.uri().query().unwrap_or("");
-        let new_query = self.rewrite_token_scope(query);
+        let (registry, new_query) = self.rewrite_token_scope(query);
         
-        let mut url = self.state.registry.token_endpoint.clone();
+        let mut url = registry.token_endpoint.clone();
         url.set_query(Some(&new_query));
         
         info!("rewrote token: {} into {}", req.uri(), url);
@@ -164,8 +949,7 @@ impl ProxyService {
             headers.insert(HOST, host_value);
         }
         
-        let body = req.into_body().collect().await.map_err(|e| Box::new(e) as BoxError)?.to_bytes();
-        let body = Full::new(body).map_err(|e: std::convert::Infallible| match e {}).boxed();
+        let body = self.request_body(req.into_body(), "token");
         
         let new_uri = Uri::try_from(url.as_str()).map_err(|e| Box::new(e) as BoxError)?;
         let mut client_req = Request::builder()
@@ -178,7 +962,7 @@ impl ProxyService {
         
         let client_req = client_req.body(body).map_err(|e| Box::new(e) as BoxError)?;
         
-        let client_resp = self.client.request(client_req).await
+        let client_resp = self.upstream(client_req).await
             .map_err(|e| {
                 tracing::error!("Failed to execute token request: {}", e);
                 Box::new(e) as BoxError
@@ -199,14 +983,11 @@ impl ProxyService {
     }
 
     fn handle_redirect(&self, uri: &Uri) -> Result<Response<BoxBody>, BoxError> {
-        let path = uri.path();
-        let registry = &self.state.registry;
+        let path = uri.path().trim_start_matches('/');
+        let (registry, name) = self.state.route(path)
+            .unwrap_or_else(|| (self.state.default_registry(), path));
         
-        let redirect_url = format!("{}{}{}", 
-            registry.endpoint,
-            registry.repo_prefix,
-            path
-        );
+        let redirect_url = format!("{}{}", registry.endpoint, registry.upstream_name(name));
         
         let body = Full::new(Bytes::from("Redirecting...")).map_err(|e: std::convert::Infallible| match e {}).boxed();
         
@@ -221,15 +1002,304 @@ impl ProxyService {
     }
 }
 
+/// Returns the digest of a `/v2/<name>/blobs/<digest>` path.
+fn blob_digest(path: &str) -> Option<&str> {
+    let (_, digest) = path.rsplit_once("/blobs/")?;
+    if digest.starts_with("sha256:") && !digest.contains('/') {
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+fn referrers_digest(path: &str) -> Option<&str> {
+    let (_, digest) = path.rsplit_once("/referrers/")?;
+    if digest.contains(':') && !digest.contains('/') {
+        Some(digest)
+    } else {
+        None
+    }
+}
+
+fn cached_blob_response(method: &Method, digest: &str, body: BoxBody, len: u64) -> Result<Response<BoxBody>, BoxError> {
+    let body = if method == Method::HEAD {
+        Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed()
+    } else {
+        body
+    };
+    
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .header(CONTENT_LENGTH, len)
+        .header("Docker-Content-Digest", digest)
+        .body(body)
+        .map_err(|e| {
+            tracing::error!("Failed to build cached blob response: {}", e);
+            Box::new(e) as BoxError
+        })
+}
+
+/// Returns the tag or digest of a `/v2/<name>/manifests/<reference>` path.
+fn manifest_reference(path: &str) -> Option<&str> {
+    let (_, reference) = path.rsplit_once("/manifests/")?;
+    if !reference.is_empty() && !reference.contains('/') {
+        Some(reference)
+    } else {
+        None
+    }
+}
+
+fn cached_manifest_response(method: &Method, manifest: CachedManifest) -> Result<Response<BoxBody>, BoxError> {
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_LENGTH, manifest.body.len());
+    if let Some(content_type) = &manifest.content_type {
+        response = response.header(CONTENT_TYPE, content_type);
+    }
+    if let Some(digest) = &manifest.digest {
+        response = response.header("Docker-Content-Digest", digest);
+    }
+    
+    let body = if method == Method::HEAD {
+        Empty::new().map_err(|e: std::convert::Infallible| match e {}).boxed()
+    } else {
+        Full::new(manifest.body).map_err(|e: std::convert::Infallible| match e {}).boxed()
+    };
+    response.body(body).map_err(|e| {
+        tracing::error!("Failed to build cached manifest response: {}", e);
+        Box::new(e) as BoxError
+    })
+}
+
+/// Whether a request failed because its streamed body was cut off at `max_body_size`.
+fn exceeds_body_limit(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        let inner = error.downcast_ref::<std::io::Error>().and_then(|e| e.get_ref());
+        if inner.is_some_and(|e| e.is::<LengthLimitError>()) {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+/// Builds an error response in the format defined by the distribution spec.
+fn error_response(status: StatusCode, code: &str, message: &str) -> Result<Response<BoxBody>, BoxError> {
+    let body = format!(r#"{{"errors":[{{"code":"{}","message":"{}"}}]}}"#, code, message);
+    let body = Full::new(Bytes::from(body)).map_err(|e: std::convert::Infallible| match e {}).boxed();
+    
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body)
+        .map_err(|e| {
+            tracing::error!("Failed to build error response: {}", e);
+            Box::new(e) as BoxError
+        })
+}
+
+/// The route a request is accounted to in the metrics.
+fn route_label(path: &str) -> &'static str {
+    if path.starts_with("/v2/") {
+        "api"
+    } else if path.strip_prefix('/').and_then(|p| p.strip_suffix("/token")) == Some(PACKAGE_NAME) {
+        "token"
+    } else if path == "/metrics" {
+        "metrics"
+    } else if path == "/healthz" || path == "/readyz" {
+        "health"
+    } else {
+        "redirect"
+    }
+}
+
+fn health_response(result: Result<(), String>) -> Result<Response<BoxBody>, BoxError> {
+    let (status, message) = match result {
+        Ok(()) => (StatusCode::OK, "ok".to_string()),
+        Err(e) => {
+            tracing::warn!("Readiness check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, e)
+        }
+    };
+    
+    let body = Full::new(Bytes::from(message)).map_err(|e: std::convert::Infallible| match e {}).boxed();
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body)
+        .map_err(|e| {
+            tracing::error!("Failed to build health response: {}", e);
+            Box::new(e) as BoxError
+        })
+}
+
+fn handle_metrics() -> Result<Response<BoxBody>, BoxError> {
+    let body = Full::new(Bytes::from(METRICS.encode())).map_err(|e: std::convert::Infallible| match e {}).boxed();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body)
+        .map_err(|e| {
+            tracing::error!("Failed to build metrics response: {}", e);
+            Box::new(e) as BoxError
+        })
+}
+
+/// Returns the repository name of a `<name>/...` API path below `/v2/`.
+fn repository_name(path: &str) -> Option<&str> {
+    ["/manifests/", "/blobs/", "/tags/", "/referrers/"].iter()
+        .filter_map(|marker| path.rfind(marker))
+        .max()
+        .map(|end| &path[..end])
+}
+
+/// Whether an API path names a valid repository, and has no dot segments or escapes that the
+/// upstream URL would resolve to a different path than the one checked.
+fn valid_api_path(path: &str) -> bool {
+    let path = path.strip_prefix("/v2/").unwrap_or(path);
+    !path.contains('%')
+        && !path.split('/').any(|segment| segment == "." || segment == "..")
+        && repository_name(path).is_none_or(valid_name)
+}
+
+/// Replaces the upstream repository name in a tag list with the name requested by the client.
+fn rewrite_tag_list(body: Bytes, name: &str) -> Bytes {
+    let Ok(mut list) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return body;
+    };
+    if let Some(field) = list.get_mut("name") {
+        *field = serde_json::Value::from(name);
+    }
+    serde_json::to_vec(&list).map(Bytes::from).unwrap_or(body)
+}
+
+/// Parses the `key=value` parameters of an authentication challenge, where values may be
+/// quoted to contain commas.
+fn challenge_params(params: &str) -> Vec<(String, String)> {
+    let mut parsed = Vec::new();
+    let mut rest = params.trim_start();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim_matches(|c: char| c == ',' || c.is_whitespace());
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        parsed.push((key.to_string(), value.trim().to_string()));
+        rest = after;
+    }
+    parsed
+}
+
+/// Maps a repository scope on the upstream registry back to the name seen by clients.
+/// Returns `None` for repositories outside of the repository prefix.
+fn client_scope(registry: &Registry, scope: &str) -> Option<String> {
+    let Some(rest) = scope.strip_prefix("repository:") else {
+        return Some(scope.to_string());
+    };
+    let (name, actions) = rest.rsplit_once(':')?;
+    Some(format!("repository:{}:{}", registry.client_name(name)?, actions))
+}
+
+/// The token action required for a request method.
+fn action(method: &Method) -> &'static str {
+    match *method {
+        Method::GET | Method::HEAD => "pull",
+        Method::DELETE => "delete",
+        _ => "push",
+    }
+}
+
+/// The token scope a request to `url` on the upstream registry needs.
+fn upstream_scope(method: &Method, url: &Url) -> Option<String> {
+    let path = url.path().strip_prefix("/v2/").unwrap_or_default();
+    if path == "_catalog" {
+        Some("registry:catalog:*".to_string())
+    } else {
+        repository_name(path).map(|name| format!("repository:{}:{}", name, scope_actions(method)))
+    }
+}
+
+/// Builds the request forwarded to the upstream registry.
+fn upstream_request(method: &Method, uri: &Uri, headers: &http::HeaderMap, body: BoxBody) -> Result<Request<BoxBody>, BoxError> {
+    let mut req = Request::builder()
+        .method(method.clone())
+        .uri(uri.clone());
+    for (key, value) in headers.iter() {
+        req = req.header(key, value);
+    }
+    req.body(body).map_err(|e| Box::new(e) as BoxError)
+}
+
+/// The actions to request in a token scope for a request method. Pushing requires pulling as
+/// well, e.g. to check for existing blobs.
+fn scope_actions(method: &Method) -> &'static str {
+    match action(method) {
+        "push" => "pull,push",
+        action => action,
+    }
+}
+
+fn bearer_challenge(realm: &str, scope: Option<&str>, error: Option<&str>) -> Result<Response<BoxBody>, BoxError> {
+    let mut challenge = format!("Bearer realm=\"{}\",service=\"{}\"", realm, token::SERVICE);
+    if let Some(scope) = scope {
+        challenge.push_str(&format!(",scope=\"{}\"", scope));
+    }
+    if let Some(error) = error {
+        challenge.push_str(&format!(",error=\"{}\"", error));
+    }
+    
+    let (code, message) = match error {
+        Some(_) => ("DENIED", "requested access to the resource is denied"),
+        None => ("UNAUTHORIZED", "authentication required"),
+    };
+    let mut response = error_response(StatusCode::UNAUTHORIZED, code, message)?;
+    response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge)?);
+    Ok(response)
+}
+
+/// Answers the `/v2/` ping of clients holding a token issued by conex.
+fn api_version_response() -> Result<Response<BoxBody>, BoxError> {
+    let body = Full::new(Bytes::from("{}")).map_err(|e: std::convert::Infallible| match e {}).boxed();
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .header("Docker-Distribution-API-Version", "registry/2.0")
+        .body(body)
+        .map_err(|e| {
+            tracing::error!("Failed to build response: {}", e);
+            Box::new(e) as BoxError
+        })
+}
+
+fn unauthorized_response() -> Result<Response<BoxBody>, BoxError> {
+    let mut response = error_response(StatusCode::UNAUTHORIZED, "UNAUTHORIZED", "authentication required")?;
+    let challenge = format!("Basic realm=\"{}\"", PACKAGE_NAME);
+    response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_str(&challenge)?);
+    Ok(response)
+}
+
 impl Service<Request<Incoming>> for ProxyService {
     type Response = Response<BoxBody>;
     type Error = BoxError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        let service = self.clone();
+        let mut service = self.clone();
+        service.state = self.states.borrow().clone();
         Box::pin(async move {
-            service.proxy_request(req).await
+            let route = route_label(req.uri().path());
+            let start = Instant::now();
+            let result = service.proxy_request(req).await;
+            
+            METRICS.request_duration.with_label_values(&[route]).observe(start.elapsed().as_secs_f64());
+            let status = result.as_ref().map_or_else(|_| "error".to_string(), |response| response.status().as_u16().to_string());
+            METRICS.requests.with_label_values(&[route, status.as_str()]).inc();
+            
+            result.map(|response| response.map(|body| {
+                CountingBody::new(body, METRICS.bytes.with_label_values(&[route, "sent"])).boxed()
+            }))
         })
     }
 }
\ No newline at end of file
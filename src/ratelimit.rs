@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::BucketConfig;
+
+/// Number of tracked clients above which idle buckets are purged.
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    Manifest,
+    Blob,
+}
+
+/// Token bucket rate limiting per client, with separate limits for manifest and blob requests.
+#[derive(Debug)]
+pub struct RateLimiter {
+    manifests: Option<BucketConfig>,
+    blobs: Option<BucketConfig>,
+    buckets: Mutex<HashMap<(RequestKind, String), Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(manifests: Option<BucketConfig>, blobs: Option<BucketConfig>) -> Self {
+        Self { manifests, blobs, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether the limiter enforces exactly these limits.
+    pub(crate) fn limits(&self, manifests: &Option<BucketConfig>, blobs: &Option<BucketConfig>) -> bool {
+        self.manifests == *manifests && self.blobs == *blobs
+    }
+
+    /// Takes a token from the bucket of `client` for `kind`. If the bucket is empty, returns
+    /// how long until a token is available.
+    pub fn check(&self, kind: RequestKind, client: &str) -> Result<(), Duration> {
+        let config = match kind {
+            RequestKind::Manifest => &self.manifests,
+            RequestKind::Blob => &self.blobs,
+        };
+        let Some(config) = config else {
+            return Ok(());
+        };
+        let burst = config.burst();
+        let now = Instant::now();
+        
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_CLIENTS {
+            // Buckets that have refilled completely are indistinguishable from new ones.
+            buckets.retain(|(kind, _), bucket| {
+                let rate = match kind {
+                    RequestKind::Manifest => self.manifests.as_ref(),
+                    RequestKind::Blob => self.blobs.as_ref(),
+                };
+                rate.is_some_and(|rate| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate.rate < rate.burst())
+            });
+        }
+        
+        let bucket = buckets.entry((kind, client.to_string()))
+            .or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * config.rate).min(burst);
+        bucket.updated = now;
+        
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / config.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(rate: f64, burst: Option<f64>) -> Option<BucketConfig> {
+        Some(BucketConfig { rate, burst })
+    }
+
+    #[test]
+    fn burst_is_allowed_then_limited() {
+        let limiter = RateLimiter::new(bucket(2.0, Some(3.0)), None);
+        for _ in 0..3 {
+            assert!(limiter.check(RequestKind::Manifest, "a").is_ok());
+        }
+        let wait = limiter.check(RequestKind::Manifest, "a").unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(500), "{:?}", wait);
+    }
+
+    #[test]
+    fn clients_and_kinds_have_separate_buckets() {
+        let limiter = RateLimiter::new(bucket(1.0, None), None);
+        assert!(limiter.check(RequestKind::Manifest, "a").is_ok());
+        assert!(limiter.check(RequestKind::Manifest, "a").is_err());
+        assert!(limiter.check(RequestKind::Manifest, "b").is_ok());
+        // Blobs are not limited.
+        for _ in 0..10 {
+            assert!(limiter.check(RequestKind::Blob, "a").is_ok());
+        }
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = RateLimiter::new(None, bucket(100.0, Some(1.0)));
+        assert!(limiter.check(RequestKind::Blob, "a").is_ok());
+        assert!(limiter.check(RequestKind::Blob, "a").is_err());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(limiter.check(RequestKind::Blob, "a").is_ok());
+    }
+
+    #[test]
+    fn invalid_rates_are_rejected() {
+        for (rate, burst) in [(0.0, None), (-1.0, None), (f64::NAN, None), (f64::INFINITY, None), (1.0, Some(f64::NAN)), (1.0, Some(0.0))] {
+            let config = BucketConfig { rate, burst };
+            assert!(config.validate("test").is_err(), "{} / {:?}", rate, burst);
+        }
+        assert!(BucketConfig { rate: 0.5, burst: Some(10.0) }.validate("test").is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::watch;
+use tracing::{error, info};
+
+use crate::{AppState, Config};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reloads the [`AppState`] whenever the configuration file or a secret file it names changes,
+/// or the process receives `SIGHUP`, and publishes it to every [`ProxyService`](crate::ProxyService).
+///
+/// Requests already in flight keep the state they started with. If the new configuration
+/// cannot be loaded, the previous state stays in use.
+pub fn spawn_reloader(path: Option<PathBuf>, tx: watch::Sender<Arc<AppState>>) {
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        let secrets = Config::load(path.as_deref()).map(|c| c.secret_files()).unwrap_or_default();
+        let mut watched = watch_files(path.iter().cloned().chain(secrets));
+        
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading configuration"),
+                _ = interval.tick() => {
+                    let Some(changed) = changed_file(&mut watched) else {
+                        continue;
+                    };
+                    info!("{} has changed, reloading configuration", changed.display());
+                }
+            }
+            
+            let config = match Config::load(path.as_deref()) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Unable to reload configuration: {}", e);
+                    continue;
+                }
+            };
+            let previous = tx.borrow().clone();
+            match previous.reload(&config).await {
+                Ok(state) => {
+                    tx.send_replace(Arc::new(state));
+                    watched = watch_files(path.iter().cloned().chain(config.secret_files()));
+                    info!("Configuration reloaded");
+                }
+                Err(e) => error!("Unable to reload configuration: {}", e),
+            }
+        }
+    });
+}
+
+fn watch_files(paths: impl Iterator<Item = PathBuf>) -> Vec<(PathBuf, Option<SystemTime>)> {
+    paths.map(|path| {
+        let modified = modified_time(&path);
+        (path, modified)
+    }).collect()
+}
+
+/// Returns a file modified since the last check, recording the modification times of all.
+fn changed_file(watched: &mut [(PathBuf, Option<SystemTime>)]) -> Option<PathBuf> {
+    let mut changed = None;
+    for (path, modified) in watched.iter_mut() {
+        let current = modified_time(path);
+        if current != *modified {
+            *modified = current;
+            changed.get_or_insert_with(|| path.clone());
+        }
+    }
+    changed
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+struct Hangup {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangup {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| error!("Unable to listen for SIGHUP: {}", e))
+                .ok();
+            Self { signal }
+        }
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            signal.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::BoxError;
+use crate::TlsConfig;
+
+/// Builds a TLS acceptor from the configured certificate and key, or `None` if TLS is not
+/// configured.
+///
+/// ALPN offers `h2` and `http/1.1`, so the negotiated protocol decides how a connection is served.
+pub fn acceptor(config: &TlsConfig) -> Result<Option<TlsAcceptor>, BoxError> {
+    let (cert, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => return Err("Both TLS_CERT and TLS_KEY must be set to enable TLS".into()),
+    };
+    
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("Unable to load TLS certificate {}: {}", cert.display(), e))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("Unable to load TLS key {}: {}", key.display(), e))?;
+    
+    let mut server = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    
+    Ok(Some(TlsAcceptor::from(Arc::new(server))))
+}
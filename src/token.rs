@@ -0,0 +1,274 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use aws_lc_rs::hmac;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{matches, valid_name, Identity};
+use crate::GrantConfig;
+
+/// The `service` conex identifies itself as in challenges and tokens it issues.
+pub const SERVICE: &str = crate::PACKAGE_NAME;
+
+/// The scope needed to list repositories with `/v2/_catalog`.
+pub const CATALOG_SCOPE: &str = "registry:catalog:*";
+
+/// Issues and verifies HS256 signed bearer tokens, making conex its own token server.
+pub struct TokenIssuer {
+    key: hmac::Key,
+    ttl: Duration,
+    grants: Vec<GrantConfig>,
+}
+
+impl std::fmt::Debug for TokenIssuer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenIssuer")
+            .field("ttl", &self.ttl)
+            .field("grants", &self.grants)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub access: Vec<Access>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Access {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+    pub actions: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+impl TokenIssuer {
+    pub fn new(secret: &str, ttl: Duration, grants: Vec<GrantConfig>) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            ttl,
+            grants,
+        }
+    }
+
+    /// Issues a token granting the requested `scopes` as far as the grants for `identity` allow.
+    /// Scopes that are not granted at all are left out of the token.
+    pub fn issue(&self, identity: Option<&Identity>, scopes: &[String]) -> TokenResponse {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let access = scopes.iter()
+            .flat_map(|scope| scope.split(' '))
+            .filter_map(|scope| self.authorize(identity, scope))
+            .collect();
+        
+        let claims = Claims {
+            iss: SERVICE.to_string(),
+            sub: identity.map(|i| i.0.clone()).unwrap_or_default(),
+            aud: SERVICE.to_string(),
+            iat: now.as_secs(),
+            exp: (now + self.ttl).as_secs(),
+            access,
+        };
+        
+        let header = URL_SAFE_NO_PAD.encode(br#"{"alg":"HS256","typ":"JWT"}"#);
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap_or_default());
+        let signing_input = format!("{}.{}", header, payload);
+        let signature = URL_SAFE_NO_PAD.encode(hmac::sign(&self.key, signing_input.as_bytes()));
+        let token = format!("{}.{}", signing_input, signature);
+        
+        TokenResponse {
+            access_token: token.clone(),
+            token,
+            expires_in: self.ttl.as_secs(),
+        }
+    }
+
+    fn authorize(&self, identity: Option<&Identity>, scope: &str) -> Option<Access> {
+        let user = identity.map(|i| i.0.as_str());
+        if scope == CATALOG_SCOPE {
+            // The catalog is filtered by the repositories the user may pull.
+            let granted = self.grants.is_empty() || self.grants.iter().any(|grant| {
+                grant_applies(grant, user) && grant.actions.iter().any(|a| a == "*" || a == "pull")
+            });
+            return granted.then(|| Access {
+                kind: "registry".to_string(),
+                name: "catalog".to_string(),
+                actions: vec!["*".to_string()],
+            });
+        }
+        
+        let (name, actions) = scope.strip_prefix("repository:")?.rsplit_once(':')?;
+        // Patterns would match names with dot segments that resolve to other repositories.
+        if !valid_name(name) {
+            return None;
+        }
+        let granted: Vec<String> = actions.split(',')
+            .filter(|action| self.permits(user, name, action))
+            .map(String::from)
+            .collect();
+        
+        if granted.is_empty() {
+            return None;
+        }
+        Some(Access { kind: "repository".to_string(), name: name.to_string(), actions: granted })
+    }
+
+    /// Whether the grants allow `user` the `action` on the repository `name`.
+    pub fn permits(&self, user: Option<&str>, name: &str, action: &str) -> bool {
+        self.grants.is_empty() || self.grants.iter().any(|grant| {
+            grant_applies(grant, user)
+                && grant.repositories.iter().any(|r| matches(r, name))
+                && grant.actions.iter().any(|a| a == "*" || a == action)
+        })
+    }
+
+    /// Verifies the signature and expiry of `token`, returning its claims.
+    pub fn verify(&self, token: &str) -> Option<Claims> {
+        let (signing_input, signature) = token.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        hmac::verify(&self.key, signing_input.as_bytes(), &signature).ok()?;
+        
+        let (_, payload) = signing_input.split_once('.')?;
+        let claims: Claims = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if claims.aud != SERVICE || claims.exp <= now {
+            return None;
+        }
+        Some(claims)
+    }
+}
+
+fn grant_applies(grant: &GrantConfig, user: Option<&str>) -> bool {
+    grant.users.iter().any(|u| u == "*" || Some(u.as_str()) == user)
+}
+
+impl Claims {
+    /// Whether the token grants `action` on the repository `name`.
+    pub fn allows(&self, name: &str, action: &str) -> bool {
+        valid_name(name) && self.access.iter().any(|access| {
+            access.kind == "repository"
+                && access.name == name
+                && access.actions.iter().any(|a| a == action || a == "*")
+        })
+    }
+
+    /// Whether the token grants listing the repositories in the catalog.
+    pub fn allows_catalog(&self) -> bool {
+        self.access.iter().any(|access| {
+            access.kind == "registry" && access.name == "catalog" && access.actions.iter().any(|a| a == "*")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(users: &[&str], repositories: &[&str], actions: &[&str]) -> GrantConfig {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        GrantConfig { users: strings(users), repositories: strings(repositories), actions: strings(actions) }
+    }
+
+    fn issue(issuer: &TokenIssuer, user: &str, scope: &str) -> Claims {
+        let identity = Identity(user.to_string());
+        let response = issuer.issue(Some(&identity), &[scope.to_string()]);
+        issuer.verify(&response.token).expect("issued token should verify")
+    }
+
+    #[test]
+    fn issued_tokens_verify() {
+        let issuer = TokenIssuer::new("secret", Duration::from_secs(300), Vec::new());
+        let claims = issue(&issuer, "alice", "repository:team/app:pull,push");
+
+        assert_eq!(claims.sub, "alice");
+        assert_eq!(claims.aud, SERVICE);
+        assert!(claims.allows("team/app", "pull"));
+        assert!(claims.allows("team/app", "push"));
+        assert!(!claims.allows("team/app", "delete"));
+        assert!(!claims.allows("team/other", "pull"));
+    }
+
+    #[test]
+    fn tampered_and_foreign_tokens_are_rejected() {
+        let issuer = TokenIssuer::new("secret", Duration::from_secs(300), Vec::new());
+        let token = issuer.issue(None, &["repository:team/app:pull".to_string()]).token;
+
+        let other = TokenIssuer::new("other", Duration::from_secs(300), Vec::new());
+        assert!(other.verify(&token).is_none());
+
+        // A payload swapped under the signature of another.
+        let (signing_input, signature) = token.rsplit_once('.').unwrap();
+        let (header, _) = signing_input.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(br#"{"iss":"conex","sub":"mallory","aud":"conex","iat":0,"exp":99999999999,"access":[]}"#);
+        assert!(issuer.verify(&format!("{}.{}.{}", header, forged, signature)).is_none());
+        assert!(issuer.verify("not a token").is_none());
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let issuer = TokenIssuer::new("secret", Duration::ZERO, Vec::new());
+        let token = issuer.issue(None, &["repository:team/app:pull".to_string()]).token;
+        assert!(issuer.verify(&token).is_none());
+    }
+
+    #[test]
+    fn grants_restrict_repositories_and_actions() {
+        let issuer = TokenIssuer::new("secret", Duration::from_secs(300), vec![
+            grant(&["alice"], &["team/*"], &["pull", "push"]),
+            grant(&["*"], &["public/*"], &["pull"]),
+        ]);
+
+        let claims = issue(&issuer, "alice", "repository:team/app:pull,push,delete");
+        assert!(claims.allows("team/app", "pull"));
+        assert!(claims.allows("team/app", "push"));
+        assert!(!claims.allows("team/app", "delete"));
+
+        let claims = issue(&issuer, "bob", "repository:team/app:pull repository:public/base:pull,push");
+        assert!(!claims.allows("team/app", "pull"));
+        assert!(claims.allows("public/base", "pull"));
+        assert!(!claims.allows("public/base", "push"));
+
+        assert!(issuer.permits(None, "public/base", "pull"));
+        assert!(!issuer.permits(None, "team/app", "pull"));
+    }
+
+    #[test]
+    fn names_with_dot_segments_are_not_granted() {
+        let issuer = TokenIssuer::new("secret", Duration::from_secs(300), vec![
+            grant(&["alice"], &["team/*"], &["pull"]),
+        ]);
+        for name in ["team/../other/x", "team/./app", "team/%2e%2e/other", "team//app"] {
+            let claims = issue(&issuer, "alice", &format!("repository:{}:pull", name));
+            assert!(claims.access.is_empty(), "{}", name);
+            assert!(!claims.allows(name, "pull"), "{}", name);
+        }
+
+        // Without grants everything valid is granted, but invalid names still are not.
+        let issuer = TokenIssuer::new("secret", Duration::from_secs(300), Vec::new());
+        let claims = issue(&issuer, "alice", "repository:team/../other/x:pull");
+        assert!(claims.access.is_empty());
+    }
+
+    #[test]
+    fn catalog_is_granted_to_users_who_may_pull() {
+        let issuer = TokenIssuer::new("secret", Duration::from_secs(300), vec![
+            grant(&["alice"], &["team/*"], &["pull"]),
+            grant(&["bob"], &["team/*"], &["push"]),
+        ]);
+        assert!(issue(&issuer, "alice", CATALOG_SCOPE).allows_catalog());
+        assert!(!issue(&issuer, "bob", CATALOG_SCOPE).allows_catalog());
+        assert!(!issue(&issuer, "alice", "repository:team/app:pull").allows_catalog());
+    }
+}
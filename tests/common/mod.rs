@@ -0,0 +1,137 @@
+//! A mock upstream registry and a conex instance proxying it, shared by the integration tests.
+
+#![allow(dead_code)]
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use conex::{AppState, Config, ProxyService, RegistryConfig};
+use http::header::{HOST, LOCATION, WWW_AUTHENTICATE};
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use url::form_urlencoded;
+
+/// A request received by the mock upstream.
+#[derive(Debug)]
+pub struct Received {
+    pub method: Method,
+    pub path: String,
+    pub query: Option<String>,
+    pub body: Bytes,
+}
+
+impl Received {
+    pub fn param(&self, name: &str) -> Option<String> {
+        form_urlencoded::parse(self.query.as_deref().unwrap_or("").as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+}
+
+pub type Log = Arc<Mutex<Vec<Received>>>;
+
+/// Answers like a registry accepting blob uploads to any repository.
+async fn upstream(req: Request<Incoming>, addr: SocketAddr, log: Log) -> Result<Response<Full<Bytes>>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().map(String::from);
+    let body = req.into_body().collect().await.map(|b| b.to_bytes()).unwrap_or_default();
+    let received = Received { method: method.clone(), path: path.clone(), query, body };
+    let mount = received.param("mount");
+    log.lock().unwrap().push(received);
+
+    let name = path.strip_prefix("/v2/").and_then(|p| p.split_once("/blobs/")).map(|(name, _)| name);
+    let response = match (method, name) {
+        (Method::GET, None) if path == "/v2/" => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header(WWW_AUTHENTICATE, format!("Bearer realm=\"http://{}/token\",service=\"mock\"", addr)),
+        (Method::POST, Some(name)) => match mount {
+            Some(digest) => Response::builder()
+                .status(StatusCode::CREATED)
+                .header(LOCATION, format!("/v2/{}/blobs/{}", name, digest)),
+            None => Response::builder()
+                .status(StatusCode::ACCEPTED)
+                .header(LOCATION, format!("/v2/{}/blobs/uploads/uuid1?_state=a", name)),
+        },
+        (Method::PATCH, Some(name)) => Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(LOCATION, format!("http://{}/v2/{}/blobs/uploads/uuid1?_state=b", addr, name)),
+        (Method::PUT, Some(name)) => Response::builder()
+            .status(StatusCode::CREATED)
+            .header(LOCATION, format!("/v2/{}/blobs/sha256:abc", name)),
+        _ => Response::builder().status(StatusCode::NOT_FOUND),
+    };
+    Ok(response.body(Full::new(Bytes::new())).unwrap())
+}
+
+/// Starts the mock upstream, returning its address and the requests it receives.
+pub async fn start_upstream() -> (SocketAddr, Log) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let log = Log::default();
+
+    let received = log.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let log = received.clone();
+            let service = service_fn(move |req| upstream(req, addr, log.clone()));
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    (addr, log)
+}
+
+/// Starts conex proxying the repositories below `p` on `upstream`, except for `secret/*`.
+pub async fn start_conex(upstream: SocketAddr) -> SocketAddr {
+    let config = Config {
+        registry: Some(RegistryConfig {
+            host: Some(format!("http://{}", upstream)),
+            prefix: Some("p".to_string()),
+            deny: vec!["secret/*".to_string()],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let state = AppState::new(&config).await.unwrap();
+    let (states, _) = watch::channel(Arc::new(state));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = ProxyService::new(states.subscribe());
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    addr
+}
+
+pub async fn send(conex: SocketAddr, method: Method, path: &str, body: &'static [u8]) -> Response<Incoming> {
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let req = Request::builder()
+        .method(method)
+        .uri(format!("http://{}{}", conex, path))
+        .header(HOST, format!("localhost:{}", conex.port()))
+        .body(Full::new(Bytes::from_static(body)))
+        .unwrap();
+    client.request(req).await.unwrap()
+}
+
+pub fn location(resp: &Response<Incoming>) -> &str {
+    resp.headers().get(LOCATION).unwrap().to_str().unwrap()
+}
+
+/// The requests received by the upstream, except for the `/v2/` ping made on startup.
+pub fn uploads(log: &Log) -> Vec<Received> {
+    log.lock().unwrap().drain(..).filter(|r| r.path != "/v2/").collect()
+}
+
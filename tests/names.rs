@@ -0,0 +1,53 @@
+//! Repository names that would resolve to another repository once put into the upstream URL,
+//! bypassing the allow and deny lists, are rejected before reaching the upstream.
+
+mod common;
+
+use http::{Method, StatusCode};
+
+use common::{send, start_conex, start_upstream, uploads};
+
+#[tokio::test]
+async fn dot_segments_are_rejected() {
+    let (upstream, log) = start_upstream().await;
+    let conex = start_conex(upstream).await;
+
+    for path in [
+        "/v2/app/../secret/x/blobs/uploads/",
+        "/v2/app/../../escape/blobs/uploads/",
+        "/v2/app/%2e%2e/secret/x/blobs/uploads/",
+        "/v2/app/%2E%2E/secret/x/blobs/uploads/",
+        "/v2/./secret/x/blobs/uploads/",
+        "/v2/app/blobs/uploads/../../../secret/x/blobs/uploads/",
+        "/v2/app/manifests/../../../escape/manifests/latest",
+    ] {
+        let resp = send(conex, Method::POST, path, b"").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", path);
+    }
+    assert!(uploads(&log).is_empty());
+}
+
+#[tokio::test]
+async fn invalid_names_are_rejected() {
+    let (upstream, log) = start_upstream().await;
+    let conex = start_conex(upstream).await;
+
+    for path in ["/v2/App/blobs/uploads/", "/v2/app//x/blobs/uploads/", "/v2/app-/blobs/uploads/"] {
+        let resp = send(conex, Method::POST, path, b"").await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", path);
+    }
+    assert!(uploads(&log).is_empty());
+}
+
+#[tokio::test]
+async fn mount_from_dot_segments_falls_back_to_upload() {
+    let (upstream, log) = start_upstream().await;
+    let conex = start_conex(upstream).await;
+
+    let resp = send(conex, Method::POST, "/v2/app/blobs/uploads/?mount=sha256:abc&from=base/../secret/base", b"").await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+
+    let received = uploads(&log);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].query, None);
+}
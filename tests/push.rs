@@ -0,0 +1,69 @@
+//! Pushes through `ProxyService` to a mock upstream registry, checking the requests the upstream
+//! receives and the upload locations returned to clients.
+
+mod common;
+
+use http::{Method, StatusCode};
+
+use common::{location, send, start_conex, start_upstream, uploads};
+
+#[tokio::test]
+async fn chunked_upload() {
+    let (upstream, log) = start_upstream().await;
+    let conex = start_conex(upstream).await;
+
+    let resp = send(conex, Method::POST, "/v2/app/blobs/uploads/", b"").await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    assert_eq!(location(&resp), "/v2/app/blobs/uploads/uuid1?_state=a");
+
+    let resp = send(conex, Method::PATCH, location(&resp), b"hello").await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    let origin = format!("http://localhost:{}", conex.port());
+    assert_eq!(location(&resp), format!("{}/v2/app/blobs/uploads/uuid1?_state=b", origin));
+
+    let next = location(&resp).strip_prefix(&origin).unwrap().to_string();
+    let resp = send(conex, Method::PUT, &format!("{}&digest=sha256:abc", next), b" world").await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(location(&resp), "/v2/app/blobs/sha256:abc");
+
+    let received = uploads(&log);
+    let requests = received.iter()
+        .map(|r| (r.method.as_str(), r.path.as_str(), r.query.as_deref(), &r.body[..]))
+        .collect::<Vec<_>>();
+    assert_eq!(requests, [
+        ("POST", "/v2/p/app/blobs/uploads/", None, &b""[..]),
+        ("PATCH", "/v2/p/app/blobs/uploads/uuid1", Some("_state=a"), b"hello"),
+        ("PUT", "/v2/p/app/blobs/uploads/uuid1", Some("_state=b&digest=sha256:abc"), b" world"),
+    ]);
+}
+
+#[tokio::test]
+async fn cross_repository_mount() {
+    let (upstream, log) = start_upstream().await;
+    let conex = start_conex(upstream).await;
+
+    let resp = send(conex, Method::POST, "/v2/app/blobs/uploads/?mount=sha256:abc&from=base", b"").await;
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(location(&resp), "/v2/app/blobs/sha256:abc");
+
+    let received = uploads(&log);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].path, "/v2/p/app/blobs/uploads/");
+    assert_eq!(received[0].param("mount").as_deref(), Some("sha256:abc"));
+    assert_eq!(received[0].param("from").as_deref(), Some("p/base"));
+}
+
+#[tokio::test]
+async fn mount_from_denied_repository_falls_back_to_upload() {
+    let (upstream, log) = start_upstream().await;
+    let conex = start_conex(upstream).await;
+
+    let resp = send(conex, Method::POST, "/v2/app/blobs/uploads/?mount=sha256:abc&from=secret/base", b"").await;
+    assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    assert_eq!(location(&resp), "/v2/app/blobs/uploads/uuid1?_state=a");
+
+    let received = uploads(&log);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].path, "/v2/p/app/blobs/uploads/");
+    assert_eq!(received[0].query, None);
+}
@@ -0,0 +1,60 @@
+//! Reloading the configuration keeps the parts of the state it leaves unchanged, so that tokens,
+//! rate limits and discovered token endpoints survive a reload.
+
+mod common;
+
+use std::sync::Arc;
+
+use conex::{AppState, BucketConfig, Config, RegistryConfig};
+
+use common::{start_upstream, Log};
+
+fn config(upstream: std::net::SocketAddr) -> Config {
+    let mut config = Config {
+        registry: Some(RegistryConfig {
+            host: Some(format!("http://{}", upstream)),
+            prefix: Some("p".to_string()),
+            auth_header: Some("Basic dXNlcjpwYXNz".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    config.rate_limit.blobs = Some(BucketConfig { rate: 10.0, burst: None });
+    config
+}
+
+fn discoveries(log: &Log) -> usize {
+    log.lock().unwrap().drain(..).filter(|r| r.path == "/v2/").count()
+}
+
+#[tokio::test]
+async fn unchanged_configuration_keeps_state() {
+    let (upstream, log) = start_upstream().await;
+    let state = AppState::new(&config(upstream)).await.unwrap();
+    assert_eq!(discoveries(&log), 1);
+
+    let reloaded = state.reload(&config(upstream)).await.unwrap();
+    assert_eq!(discoveries(&log), 0);
+    assert!(Arc::ptr_eq(state.rate_limiter.as_ref().unwrap(), reloaded.rate_limiter.as_ref().unwrap()));
+    assert!(Arc::ptr_eq(&state.readiness, &reloaded.readiness));
+    assert!(Arc::ptr_eq(state.registries[0].auth.as_ref().unwrap(), reloaded.registries[0].auth.as_ref().unwrap()));
+}
+
+#[tokio::test]
+async fn changed_configuration_replaces_state() {
+    let (upstream, log) = start_upstream().await;
+    let state = AppState::new(&config(upstream)).await.unwrap();
+    assert_eq!(discoveries(&log), 1);
+
+    let mut changed = config(upstream);
+    changed.rate_limit.blobs = Some(BucketConfig { rate: 20.0, burst: None });
+    changed.registry.as_mut().unwrap().auth_header = Some("Basic b3RoZXI6cGFzcw==".to_string());
+    let reloaded = state.reload(&changed).await.unwrap();
+
+    // The host is the same, so its token endpoint is not discovered again.
+    assert_eq!(discoveries(&log), 0);
+    assert_eq!(reloaded.registries[0].token_endpoint, state.registries[0].token_endpoint);
+    assert!(!Arc::ptr_eq(state.rate_limiter.as_ref().unwrap(), reloaded.rate_limiter.as_ref().unwrap()));
+    assert!(!Arc::ptr_eq(state.registries[0].auth.as_ref().unwrap(), reloaded.registries[0].auth.as_ref().unwrap()));
+    assert_eq!(reloaded.registries[0].auth.as_ref().unwrap().authorization(None).await.unwrap(), "Basic b3RoZXI6cGFzcw==");
+}